@@ -4,7 +4,7 @@ extern crate test;
 extern crate utp;
 
 use test::Bencher;
-use utp::UtpSocket;
+use utp::{UtpContext, UtpSocket};
 use std::sync::Arc;
 use std::thread;
 
@@ -106,3 +106,35 @@ fn bench_transfer_one_megabyte(b: &mut Bencher) {
     });
     b.bytes = len as u64;
 }
+
+#[bench]
+fn bench_next_deadline_of_idle_connections(b: &mut Bencher) {
+    use std::time::Instant;
+    const CONNECTIONS: usize = 1000;
+    let server = iotry!(UtpContext::bind(next_test_ip4()));
+    let server_addr = iotry!(server.local_addr());
+    let clients = iotry!(UtpContext::bind(next_test_ip4()));
+    let acceptor = thread::spawn(move || {
+        (0..CONNECTIONS).map(|_| iotry!(server.accept()).0).collect::<Vec<_>>()
+    });
+    let mut sockets: Vec<_> = (0..CONNECTIONS).map(|_| iotry!(clients.connect(server_addr)))
+                                              .collect();
+    let mut accepted = acceptor.join().unwrap();
+
+    // Start every connection's retransmission timer, as an event loop reading them would
+    let mut buf = [0; 1500];
+    for socket in &mut sockets {
+        iotry!(socket.set_nonblocking(true));
+        assert!(socket.recv_from(&mut buf).is_err());
+    }
+
+    // Nothing is due, an event loop would sleep until the deadline
+    assert!(clients.next_deadline().unwrap() > Instant::now());
+    b.iter(|| clients.next_deadline());
+
+    // Closing the connections would wait for unattended remote peers to reply, one connection
+    // after another: reset them instead
+    for socket in sockets.iter_mut().chain(accepted.iter_mut()) {
+        iotry!(socket.set_linger(None));
+    }
+}
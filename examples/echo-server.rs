@@ -25,11 +25,8 @@ fn main() {
     let addr = "127.0.0.1:8080";
     let listener = UtpListener::bind(addr).expect("Error binding listener");
 
-    for connection in listener.incoming() {
-        // Spawn a new handler for each new connection
-        match connection {
-            Ok((socket, _src)) => { thread::spawn(move || { handle_client(socket) }); },
-            _ => ()
-        }
+    // Spawn a new handler for each new connection, skipping failed handshakes
+    for (socket, _src) in listener.incoming().flatten() {
+        thread::spawn(move || handle_client(socket));
     }
 }
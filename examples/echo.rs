@@ -0,0 +1,77 @@
+//! An echo server and the matching client.
+//!
+//! Run the server with `cargo run --example echo -- -s 127.0.0.1:8080`, then the client with
+//! `cargo run --example echo -- -c 127.0.0.1:8080`. The client sends whatever it reads from
+//! stdin and prints what the server sends back, until stdin ends.
+extern crate env_logger;
+extern crate utp;
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::process;
+use std::thread;
+use utp::{UtpListener, UtpStream};
+
+fn usage() -> ! {
+    println!("Usage: echo [-s|-c] <address>");
+    process::exit(1);
+}
+
+/// Sends everything received on the connection back, until the remote peer closes it.
+fn echo(mut stream: UtpStream) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+        match stream.read(&mut buf)? {
+            0 => break,
+            read => stream.write_all(&buf[..read])?,
+        }
+    }
+    stream.close()?;
+    Ok(())
+}
+
+fn serve(addr: &str) {
+    let listener = UtpListener::bind(addr).expect("Error binding listener");
+    eprintln!("Serving on {}", addr);
+
+    // Spawn a new handler for each new connection, skipping failed handshakes
+    for (socket, src) in listener.incoming().flatten() {
+        thread::spawn(move || {
+            eprintln!("{} connected", src);
+            match echo(UtpStream::from(socket)) {
+                Ok(()) => eprintln!("{} disconnected", src),
+                Err(e) => eprintln!("{}: {}", src, e),
+            }
+        });
+    }
+}
+
+fn connect(addr: &str) {
+    let mut stream = UtpStream::connect(addr).expect("Error connecting to remote peer");
+    let mut reader = stream.try_clone().expect("Error cloning stream");
+
+    // Print the replies as they arrive, until the server closes its half of the connection
+    let printer = thread::spawn(move || io::copy(&mut reader, &mut io::stdout()));
+
+    io::copy(&mut io::stdin(), &mut stream).expect("Error writing to stream");
+
+    // Let the server know nothing else is coming, while still reading its replies
+    stream.shutdown(Shutdown::Write).expect("Error shutting down stream");
+    printer.join().unwrap().expect("Error reading from stream");
+    stream.close().expect("Error closing stream");
+}
+
+fn main() {
+    // Start logging
+    env_logger::init().expect("Error starting logger");
+
+    let mut args = std::env::args().skip(1);
+    let mode = args.next();
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+
+    match mode {
+        Some(ref s) if s == "-s" => serve(&addr),
+        Some(ref s) if s == "-c" => connect(&addr),
+        _ => usage(),
+    }
+}
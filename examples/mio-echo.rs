@@ -0,0 +1,75 @@
+//! An echo server driven by a mio event loop, serving one connection after another.
+//!
+//! Run it with `cargo run --features mio --example mio-echo -- 127.0.0.1:8080`, then connect to it
+//! with the client of the `echo` example: `cargo run --example echo -- -c 127.0.0.1:8080`.
+extern crate env_logger;
+extern crate mio;
+extern crate utp;
+
+use mio::{Events, Interest, Poll, Token};
+use std::io::{self, ErrorKind};
+use utp::UtpSocket;
+
+const SOCKET: Token = Token(0);
+
+/// Accepts a connection on `addr` and sends everything received on it back, until the remote
+/// peer closes it.
+fn serve_one(poll: &mut Poll, addr: &str) -> io::Result<()> {
+    let mut socket = UtpSocket::bind(addr)?;
+    socket.set_nonblocking(true)?;
+    poll.registry().register(&mut socket, SOCKET, Interest::READABLE)?;
+
+    let mut events = Events::with_capacity(16);
+    let mut buf = [0; 4096];
+    let mut pending = Vec::new();
+    let mut closed = false;
+    while !closed {
+        // Wake up when packets arrive, or when the socket's timers are due
+        poll.poll(&mut events, socket.next_timeout())?;
+
+        // Readiness is edge-triggered: read until nothing is left, which also fires the timers
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((0, _src)) => { closed = true; break; }
+                Ok((read, _src)) => pending.extend_from_slice(&buf[..read]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Send back as much as the send buffer takes, the rest once acknowledgements free it up
+        while !pending.is_empty() {
+            match socket.send_to(&pending) {
+                Ok(written) => { pending.drain(..written); }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    // The remote peer sends nothing else: send the rest and close the connection, waiting for it
+    poll.registry().deregister(&mut socket)?;
+    socket.set_nonblocking(false)?;
+    while !pending.is_empty() {
+        let written = socket.send_to(&pending)?;
+        pending.drain(..written);
+    }
+    socket.close()?;
+    Ok(())
+}
+
+fn main() {
+    // Start logging
+    env_logger::init().expect("Error starting logger");
+
+    let addr = std::env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    let mut poll = Poll::new().expect("Error creating poll");
+    eprintln!("Serving on {}", addr);
+
+    loop {
+        match serve_one(&mut poll, &addr) {
+            Ok(()) => eprintln!("Connection closed"),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use std::future::{self, Future};
+use std::io::{self, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+use tokio::time::{self, Sleep};
+use crate::error;
+use crate::socket::{UtpListener, UtpSocket};
+
+/// A uTP stream for the tokio runtime, implementing `AsyncRead` and `AsyncWrite`, with the
+/// `tokio` feature.
+///
+/// Connections are opened with `connect`, or accepted by an `AsyncUtpListener`. The runtime tells
+/// when packets arrive, and the retransmission timers are tokio timers: no operation blocks the
+/// runtime, nor needs a thread of its own.
+///
+/// Shutting the stream down closes the connection gracefully. Dropping it does the same in a task
+/// spawned on the runtime, which only makes progress while the runtime runs. Dropped outside of a
+/// runtime, the stream can't wait for the closing handshake, and leaves the connection to time
+/// out on the remote peer's side.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tokio::io::AsyncWriteExt;
+/// use tokio::runtime::Builder;
+/// use utp::AsyncUtpStream;
+///
+/// let runtime = Builder::new_current_thread().enable_all().build()
+///     .expect("Error starting runtime");
+/// runtime.block_on(async {
+///     let addr = "127.0.0.1:8080".parse().unwrap();
+///     let mut stream = AsyncUtpStream::connect(addr).await.expect("Error connecting");
+///     stream.write_all(b"Hi there!").await.expect("Write failed");
+///     stream.shutdown().await.expect("Error closing connection");
+/// });
+/// ```
+pub struct AsyncUtpStream {
+    /// The connection, until the stream is dropped
+    connection: Option<Connection>,
+}
+
+/// A non-blocking socket along with what it takes to wait for it on the runtime.
+struct Connection {
+    socket: UtpSocket,
+    /// The UDP socket of `socket`, which the runtime reports readable as packets arrive
+    udp: UdpSocket,
+    /// Wakes the task up when the timers of `socket` are due
+    timer: Pin<Box<Sleep>>,
+}
+
+impl Connection {
+    /// Makes `socket` non-blocking, to be polled on the current runtime.
+    ///
+    /// Fails with an error of kind `ErrorKind::Unsupported` if `socket` doesn't run over UDP.
+    fn new(socket: UtpSocket) -> Result<Connection> {
+        let udp = match socket.get_ref() {
+            Some(udp) => udp.try_clone()?,
+            None => return Err(io::Error::new(ErrorKind::Unsupported, "The socket isn't over UDP")),
+        };
+        socket.set_nonblocking(true)?;
+        let udp = UdpSocket::from_std(udp)?;
+        let timer = Box::pin(time::sleep(Duration::from_secs(0)));
+        Ok(Connection { socket, udp, timer })
+    }
+
+    /// Calls `op` on the socket until it stops failing with `ErrorKind::WouldBlock`, waiting for
+    /// packets to arrive or timers to be due in between.
+    ///
+    /// `op` must only fail with `ErrorKind::WouldBlock` once the UDP socket has no packet left.
+    fn poll_io<T, F>(&mut self, cx: &mut Context<'_>, mut op: F) -> Poll<Result<T>>
+        where F: FnMut(&mut UtpSocket) -> error::Result<T>
+    {
+        loop {
+            let readable = self.udp.poll_recv_ready(cx)?.is_ready();
+            let socket = &mut self.socket;
+            let mut op = || op(socket).map_err(io::Error::from);
+            let result = if readable {
+                // Having read everything clears the readiness, unless more packets arrived since
+                self.udp.try_io(Interest::READABLE, &mut op)
+            } else {
+                op()
+            };
+            match result {
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+                result => return Poll::Ready(result),
+            }
+
+            // Wait for the next packets, registering the task to be woken up by them
+            if readable {
+                continue;
+            }
+            if let Some(timeout) = self.socket.next_timeout() {
+                self.timer.as_mut().reset((Instant::now() + timeout).into());
+                if self.timer.as_mut().poll(cx).is_ready() {
+                    continue;
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+impl AsyncUtpStream {
+    /// Opens a uTP connection to `addr`, from a new UDP socket bound to any local address.
+    ///
+    /// The connection request is retried like `UtpSocket::connect` does, failing with an error
+    /// of kind `ErrorKind::TimedOut` if the remote peer never replies.
+    pub async fn connect(addr: SocketAddr) -> Result<AsyncUtpStream> {
+        let local = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let mut connection = Connection::new(UtpSocket::bind(local)?)?;
+        let (udp, socket) = (&connection.udp, &mut connection.socket);
+        let mut packet = socket.start_handshake(addr, &[]);
+
+        let mut attempt = 0;
+        loop {
+            let timeout = socket.syn_timeout(attempt)?;
+            socket.send_syn(&mut packet, attempt)?;
+            let reply = udp.async_io(Interest::READABLE, || {
+                socket.recv_syn_reply_nonblocking(addr, timeout).map_err(io::Error::from)
+            });
+            match time::timeout(timeout, reply).await {
+                Ok(result) => break result?,
+                Err(_) => debug!("Timed out, retrying"),
+            }
+            attempt += 1;
+        }
+        Ok(AsyncUtpStream { connection: Some(connection) })
+    }
+
+    /// Returns the socket address of the local half of this uTP connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.connection().socket.local_addr()?)
+    }
+
+    /// Returns the socket address of the remote half of this uTP connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.connection().socket.peer_addr()?)
+    }
+
+    fn connection(&self) -> &Connection {
+        self.connection.as_ref().expect("stream used after being dropped")
+    }
+
+    fn connection_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().expect("stream used after being dropped")
+    }
+}
+
+impl AsyncRead for AsyncUtpStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>)
+                 -> Poll<Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let connection = Pin::into_inner(self).connection_mut();
+        let unfilled = buf.initialize_unfilled();
+        let (read, _src) = ready!(connection.poll_io(cx, |socket| socket.recv_from(unfilled)))?;
+        buf.advance(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for AsyncUtpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8])
+                  -> Poll<Result<usize>> {
+        Pin::into_inner(self).connection_mut().poll_io(cx, |socket| socket.send_to(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::into_inner(self).connection_mut().poll_io(cx, UtpSocket::flush)
+    }
+
+    /// Sends the data still buffered, then closes the connection.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::into_inner(self).connection_mut().poll_io(cx, UtpSocket::close)
+    }
+}
+
+impl Drop for AsyncUtpStream {
+    /// Closes the connection in a task of its own, if it wasn't shut down.
+    fn drop(&mut self) {
+        let mut connection = match self.connection.take() {
+            Some(connection) if connection.socket.is_open() => connection,
+            _ => return,
+        };
+        match Handle::try_current() {
+            Ok(runtime) => {
+                runtime.spawn(async move {
+                    let close = future::poll_fn(|cx| connection.poll_io(cx, UtpSocket::close));
+                    if let Err(e) = close.await {
+                        debug!("Error closing dropped stream: {}", e);
+                    }
+                });
+            }
+            Err(_) => debug!("Stream dropped outside of a runtime, not closing its connection"),
+        }
+    }
+}
+
+/// A uTP listener for the tokio runtime, accepting connections as `AsyncUtpStream`s, with the
+/// `tokio` feature.
+///
+/// Like the `UtpListener` it wraps, it receives connection requests on one UDP socket and hands
+/// each connection over to a UDP socket of its own, bound to another local port.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tokio::io::AsyncReadExt;
+/// use tokio::runtime::Builder;
+/// use utp::AsyncUtpListener;
+///
+/// let runtime = Builder::new_current_thread().enable_all().build()
+///     .expect("Error starting runtime");
+/// runtime.block_on(async {
+///     let addr = "127.0.0.1:8080".parse().unwrap();
+///     let listener = AsyncUtpListener::bind(addr).await.expect("Error binding listener");
+///     loop {
+///         let (mut stream, _src) = listener.accept().await.expect("Error accepting connection");
+///         tokio::spawn(async move {
+///             let mut received = vec!();
+///             stream.read_to_end(&mut received).await.expect("Read failed");
+///         });
+///     }
+/// });
+/// ```
+pub struct AsyncUtpListener {
+    listener: UtpListener,
+    /// The UDP socket of `listener`, which the runtime reports readable as requests arrive
+    udp: UdpSocket,
+}
+
+impl AsyncUtpListener {
+    /// Creates a listener bound to `addr`, on the current runtime.
+    pub async fn bind(addr: SocketAddr) -> Result<AsyncUtpListener> {
+        let listener = UtpListener::bind(addr)?;
+        let udp = listener.udp_socket().try_clone()?;
+        udp.set_nonblocking(true)?;
+        Ok(AsyncUtpListener { udp: UdpSocket::from_std(udp)?, listener })
+    }
+
+    /// Accepts a new incoming connection, once its request arrives.
+    ///
+    /// As with `UtpListener::accept`, datagrams that aren't valid connection requests are
+    /// discarded while waiting. Several tasks may wait for connections on the same listener.
+    pub async fn accept(&self) -> Result<(AsyncUtpStream, SocketAddr)> {
+        // Over the non-blocking UDP socket, `UtpListener::accept` fails with
+        // `ErrorKind::WouldBlock` once there is no request left
+        let accept = || self.listener.accept().map_err(io::Error::from);
+        let (socket, src) = self.udp.async_io(Interest::READABLE, accept).await?;
+        let connection = Connection::new(socket)?;
+        Ok((AsyncUtpStream { connection: Some(connection) }, src))
+    }
+
+    /// Returns the local socket address of this listener.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+}
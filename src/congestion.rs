@@ -0,0 +1,385 @@
+use core::cmp::{min, max};
+use core::time::Duration;
+
+/// Maximum segment size, the largest packet (in bytes) a socket sends.
+pub const MSS: u32 = 1400;
+
+/// Minimum congestion window, in packets.
+pub const MIN_CWND: u32 = 2;
+
+const INIT_CWND: u32 = 2;
+const GAIN: f64 = 1.0;
+const ALLOWED_INCREASE: u32 = 1;
+const MAX_CWND_INCREASE_BYTES_PER_RTT: u32 = 3000; // as in libutp
+const TARGET: u64 = 100; // 100 milliseconds
+const SLOW_START_EXIT: u32 = 90; // percentage of the target delay ending slow start
+
+/// A congestion control algorithm, deciding how many bytes a socket may have in flight.
+///
+/// The socket measures delays and detects losses and timeouts, reporting them to its controller.
+/// The controller only has to keep track of the congestion window.
+pub trait CongestionController: Send + Sync {
+    /// Called whenever an acknowledgement for previously sent data arrives.
+    ///
+    /// `rtt` is the latest round-trip time sample, `queuing_delay` the current estimate of the
+    /// one-way queuing delay between the two peers, `bytes_acked` the number of bytes newly
+    /// acknowledged and `bytes_in_flight` the number of bytes sent but not yet acknowledged,
+    /// before this acknowledgement.
+    fn on_ack(&mut self, rtt: Duration, queuing_delay: Duration, bytes_acked: u32,
+              bytes_in_flight: u32);
+
+    /// Called when the remote peer reports (or the socket detects) a lost packet.
+    fn on_loss(&mut self);
+
+    /// Called when no packet arrives before the socket's retransmission timeout.
+    fn on_timeout(&mut self);
+
+    /// Returns the current congestion window, the maximum number of bytes in flight.
+    fn window(&self) -> u32;
+
+    /// Sets the queuing delay the algorithm aims for, if it targets one. Does nothing by default.
+    fn set_target_delay(&mut self, _target: Duration) {}
+
+    /// Returns the queuing delay the algorithm aims for, if it targets one. Returns `None` by
+    /// default.
+    fn target_delay(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Sets how quickly the algorithm reacts to delay variations, if it does. Does nothing by
+    /// default.
+    fn set_gain(&mut self, _gain: f64) {}
+
+    /// Sets the congestion window to start from, in bytes, before anything was sent. Does nothing
+    /// by default.
+    fn set_initial_window(&mut self, _window: u32) {}
+}
+
+/// The [LEDBAT][ledbat_rfc] congestion control algorithm, used by default.
+///
+/// LEDBAT estimates the queuing delay between the two peers and adjusts the congestion window to
+/// keep it close to a target (100 ms by default), yielding to other traffic as soon as queues
+/// start to build up. The window changes at most by `gain` packets per round trip (1 by default),
+/// and never grows by more than 3000 bytes per round trip, starting from two packets unless
+/// configured otherwise. There's no upper bound besides the 32-bit range of windows, so that paths
+/// with a large bandwidth-delay product can be filled.
+///
+/// Optionally, the connection starts with a slow start phase, like TCP: the window grows by the
+/// number of bytes acknowledged, doubling every round trip, until the queuing delay gets close to
+/// the target or a packet is lost. This reaches a large window faster, at the cost of building up
+/// queues once before yielding to other traffic.
+///
+///[ledbat_rfc]: https://tools.ietf.org/html/rfc6817
+#[derive(Debug, Clone)]
+pub struct Ledbat {
+    cwnd: u32,
+    target: Duration,
+    gain: f64,
+    slow_start: bool,
+}
+
+impl Ledbat {
+    /// Creates a controller with the initial congestion window.
+    pub fn new() -> Ledbat {
+        Ledbat {
+            cwnd: INIT_CWND * MSS,
+            target: Duration::from_millis(TARGET),
+            gain: GAIN,
+            slow_start: false,
+        }
+    }
+
+    /// Enables or disables the slow start phase. It's disabled by default.
+    ///
+    /// Enabling it after slow start ended, whether it was disabled or exited, starts it over from
+    /// the current window.
+    pub fn set_slow_start(&mut self, enabled: bool) {
+        self.slow_start = enabled;
+    }
+
+    /// Returns whether the window is in its slow start phase.
+    pub fn in_slow_start(&self) -> bool {
+        self.slow_start
+    }
+}
+
+impl Default for Ledbat {
+    fn default() -> Ledbat {
+        Ledbat::new()
+    }
+}
+
+impl CongestionController for Ledbat {
+    /// Calculates the new congestion window size, increasing it or decreasing it.
+    ///
+    /// `off_target` is a normalized value representing the difference between the current
+    /// queuing delay and the target delay. `off_target` ranges between -1.0 and 1.0. A positive
+    /// value makes the congestion window increase, while a negative value makes the congestion
+    /// window decrease.
+    ///
+    /// The window changes by `gain * off_target * bytes_acked * MSS / cwnd`, as BEP 29 specifies,
+    /// but it never grows by more than `MAX_CWND_INCREASE_BYTES_PER_RTT` bytes over a window's
+    /// worth of acknowledgements, however large the gain.
+    ///
+    /// During slow start, the window grows by `bytes_acked` instead, up to twice the bytes in
+    /// flight, until the queuing delay reaches 90% of the target.
+    fn on_ack(&mut self, _rtt: Duration, queuing_delay: Duration, bytes_acked: u32,
+              bytes_in_flight: u32) {
+        if self.slow_start {
+            if queuing_delay * 100 < self.target * SLOW_START_EXIT {
+                let cwnd = min(self.cwnd.saturating_add(bytes_acked),
+                               bytes_in_flight.saturating_mul(2));
+                self.cwnd = max(max(self.cwnd, cwnd), MIN_CWND * MSS);
+                debug!("slow start cwnd: {}", self.cwnd);
+                return;
+            }
+            debug!("queuing delay close to target, leaving slow start");
+            self.slow_start = false;
+        }
+
+        let target = self.target.as_micros() as f64;
+        let off_target = (target - queuing_delay.as_micros() as f64) / target;
+        debug!("off_target: {}", off_target);
+
+        let cwnd_increase = self.gain * off_target * bytes_acked as f64 * MSS as f64;
+        let cwnd_increase = cwnd_increase / self.cwnd as f64;
+        let max_increase = MAX_CWND_INCREASE_BYTES_PER_RTT as f64 * bytes_acked as f64 /
+                           self.cwnd as f64;
+        let cwnd_increase = cwnd_increase.min(max_increase);
+        debug!("cwnd_increase: {}", cwnd_increase);
+
+        self.cwnd = (self.cwnd as f64 + cwnd_increase) as u32;
+        let max_allowed_cwnd = bytes_in_flight.saturating_add(ALLOWED_INCREASE * MSS);
+        self.cwnd = min(self.cwnd, max_allowed_cwnd);
+        self.cwnd = max(self.cwnd, MIN_CWND * MSS);
+
+        debug!("cwnd: {}", self.cwnd);
+        debug!("max_allowed_cwnd: {}", max_allowed_cwnd);
+    }
+
+    fn on_loss(&mut self) {
+        debug!("packet loss detected, halving congestion window");
+        self.slow_start = false;
+        self.cwnd = max(self.cwnd / 2, MIN_CWND * MSS);
+        debug!("cwnd: {}", self.cwnd);
+    }
+
+    fn on_timeout(&mut self) {
+        self.slow_start = false;
+        self.cwnd = MSS;
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn set_target_delay(&mut self, target: Duration) {
+        self.target = target;
+    }
+
+    fn target_delay(&self) -> Option<Duration> {
+        Some(self.target)
+    }
+
+    fn set_gain(&mut self, gain: f64) {
+        self.gain = gain;
+    }
+
+    fn set_initial_window(&mut self, window: u32) {
+        self.cwnd = window;
+    }
+}
+
+/// A congestion controller keeping a constant window, in bytes, ignoring delay and loss signals.
+///
+/// Useful for testing, for benchmarks on controlled networks and for measuring the overhead of
+/// `Ledbat`. It shouldn't be used on shared networks, as it never yields to other traffic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedWindow(pub u32);
+
+impl CongestionController for FixedWindow {
+    fn on_ack(&mut self, _rtt: Duration, _queuing_delay: Duration, _bytes_acked: u32,
+              _bytes_in_flight: u32) {}
+
+    fn on_loss(&mut self) {}
+
+    fn on_timeout(&mut self) {}
+
+    fn window(&self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::congestion::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ledbat_grows_below_target() {
+        let mut ledbat = Ledbat::new();
+        let initial = ledbat.window();
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(10), MSS, 2 * initial);
+        assert!(ledbat.window() > initial);
+
+        // Never more than one packet above what's in flight
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(0), 10 * MSS, MSS);
+        assert_eq!(ledbat.window(), MIN_CWND * MSS);
+    }
+
+    #[test]
+    fn test_ledbat_shrinks_above_target() {
+        let mut ledbat = Ledbat::new();
+        for _ in 0..10 {
+            ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(50), MSS, 10 * MSS);
+        }
+        let window = ledbat.window();
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(200), MSS, 10 * MSS);
+        assert!(ledbat.window() < window);
+    }
+
+    #[test]
+    fn test_ledbat_target_and_gain() {
+        // A queuing delay above the default target is below a larger one
+        let mut ledbat = Ledbat::new();
+        ledbat.set_target_delay(Duration::from_millis(300));
+        let initial = ledbat.window();
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(200), MSS, 10 * MSS);
+        let grown = ledbat.window();
+        assert!(grown > initial);
+
+        // A larger gain makes the window grow faster
+        let mut ledbat = Ledbat::new();
+        ledbat.set_target_delay(Duration::from_millis(300));
+        ledbat.set_gain(2.0);
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(200), MSS, 10 * MSS);
+        assert!(ledbat.window() > grown);
+    }
+
+    #[test]
+    fn test_ledbat_window_change_matches_spec() {
+        // off_target = (100 - 25) / 100, so the window grows by 0.75 * MSS * MSS / cwnd
+        let mut ledbat = Ledbat::new();
+        assert_eq!(ledbat.window(), 2 * MSS);
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(25), MSS, 10 * MSS);
+        assert_eq!(ledbat.window(), 2 * MSS + 525);
+
+        // off_target = (100 - 150) / 100, so the window shrinks by 0.5 * MSS * MSS / cwnd
+        let mut ledbat = Ledbat::new();
+        ledbat.set_initial_window(10 * MSS);
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(150), MSS, 10 * MSS);
+        assert_eq!(ledbat.window(), 10 * MSS - 70);
+
+        // However large the gain, the window grows by at most 3000 bytes per window acknowledged
+        let mut ledbat = Ledbat::new();
+        ledbat.set_gain(10.0);
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(25), MSS, 10 * MSS);
+        assert_eq!(ledbat.window(), 2 * MSS + 1500);
+    }
+
+    #[test]
+    fn test_ledbat_loss_and_timeout() {
+        let mut ledbat = Ledbat::new();
+        for _ in 0..10 {
+            ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(0), MSS, 10 * MSS);
+        }
+        let window = ledbat.window();
+        ledbat.on_loss();
+        assert_eq!(ledbat.window(), window / 2);
+
+        // Losses never shrink the window below the minimum
+        for _ in 0..10 {
+            ledbat.on_loss();
+        }
+        assert_eq!(ledbat.window(), MIN_CWND * MSS);
+
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MSS);
+    }
+
+    /// Acknowledges a whole window, one packet at a time, as a round trip would. The sender keeps
+    /// the window full meanwhile.
+    fn ack_round_trip(ledbat: &mut Ledbat, queuing_delay: Duration) {
+        for _ in 0..ledbat.window() / MSS {
+            let in_flight = ledbat.window();
+            ledbat.on_ack(Duration::from_millis(10), queuing_delay, MSS, in_flight);
+        }
+    }
+
+    #[test]
+    fn test_ledbat_slow_start() {
+        let mut linear = Ledbat::new();
+        let mut ledbat = Ledbat::new();
+        ledbat.set_slow_start(true);
+        assert!(ledbat.in_slow_start());
+
+        // The window doubles every round trip, growing faster than linearly
+        let mut windows = vec![ledbat.window()];
+        for _ in 0..5 {
+            ack_round_trip(&mut linear, Duration::from_millis(0));
+            ack_round_trip(&mut ledbat, Duration::from_millis(0));
+            windows.push(ledbat.window());
+        }
+        for pair in windows.windows(3) {
+            assert!(pair[2] - pair[1] > pair[1] - pair[0]);
+        }
+        assert_eq!(ledbat.window(), 64 * MSS);
+        assert!(ledbat.window() > 4 * linear.window());
+        assert!(ledbat.in_slow_start());
+
+        // A queuing delay close to the target ends it
+        let window = ledbat.window();
+        ledbat.on_ack(Duration::from_millis(10), Duration::from_millis(95), MSS, window);
+        assert!(!ledbat.in_slow_start());
+        assert!(ledbat.window() <= window + MSS);
+
+        // So does a loss
+        let mut ledbat = Ledbat::new();
+        ledbat.set_slow_start(true);
+        ack_round_trip(&mut ledbat, Duration::from_millis(0));
+        ledbat.on_loss();
+        assert!(!ledbat.in_slow_start());
+        let window = ledbat.window();
+        ack_round_trip(&mut ledbat, Duration::from_millis(0));
+        assert!(ledbat.window() <= window + MSS);
+    }
+
+    #[test]
+    fn test_ledbat_fills_long_fat_pipes() {
+        // A 1 Gbps path with a 200 ms round trip holds 25 MB in flight
+        let bandwidth_delay_product = 1_000_000_000 / 8 / 5;
+        let mut ledbat = Ledbat::new();
+        ledbat.set_slow_start(true);
+        for _ in 0..20 {
+            if ledbat.window() >= bandwidth_delay_product {
+                break;
+            }
+            for _ in 0..ledbat.window() / MSS {
+                let in_flight = ledbat.window();
+                ledbat.on_ack(Duration::from_millis(200), Duration::from_millis(0), MSS,
+                              in_flight);
+            }
+        }
+        assert!(ledbat.window() > 64 * 1024);
+        assert!(ledbat.window() >= bandwidth_delay_product);
+
+        // The window saturates at the top of its range instead of overflowing
+        let mut ledbat = Ledbat::new();
+        ledbat.set_slow_start(true);
+        ledbat.set_initial_window(u32::MAX - MSS / 2);
+        ledbat.on_ack(Duration::from_millis(200), Duration::from_millis(0), MSS, u32::MAX);
+        assert_eq!(ledbat.window(), u32::MAX);
+        ledbat.set_slow_start(false);
+        ledbat.on_ack(Duration::from_millis(200), Duration::from_millis(0), MSS, u32::MAX);
+        assert_eq!(ledbat.window(), u32::MAX);
+    }
+
+    #[test]
+    fn test_fixed_window_ignores_signals() {
+        let mut fixed = FixedWindow(10 * MSS);
+        fixed.on_ack(Duration::from_millis(10), Duration::from_millis(500), MSS, 10 * MSS);
+        fixed.on_loss();
+        fixed.on_timeout();
+        assert_eq!(fixed.window(), 10 * MSS);
+    }
+}
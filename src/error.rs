@@ -1,83 +1,162 @@
 use std::error::Error;
 use std::fmt;
 use std::io::{self, ErrorKind};
+use std::result;
+use crate::packet::ParseError;
 
+/// A specialized `Result` type for uTP socket operations.
+pub type Result<T> = result::Result<T, UtpError>;
+
+/// The errors returned by `UtpSocket` and `UtpListener` operations.
+///
+/// Each error converts into an `io::Error` of the matching kind (see `kind`), which is what
+/// `UtpStream` returns, so that it can implement `Read` and `Write`. The `io::Error` holds the
+/// original error, which `get_ref` gives access to.
 #[derive(Debug)]
-pub enum SocketError {
+pub enum UtpError {
+    /// The socket is closed.
     ConnectionClosed,
+    /// The remote peer reset the connection.
     ConnectionReset,
+    /// The remote peer stopped acknowledging packets.
     ConnectionTimedOut,
+    /// No valid address was given.
     InvalidAddress,
+    /// A setting was given an invalid value, described here.
+    InvalidInput(&'static str),
+    /// The operation was cancelled, see `UtpSocket::canceller`.
+    Interrupted,
+    /// The remote peer sent a packet that couldn't be decoded.
+    InvalidPacket(ParseError),
+    /// The remote peer answered a connection request with an unexpected packet.
     InvalidReply,
+    /// The socket isn't connected to a remote peer.
     NotConnected,
+    /// The operation didn't complete before its timeout.
+    OperationTimedOut,
+    /// The requested packet size is above what a socket can send or receive.
+    PacketTooLarge,
+    /// A non-blocking socket couldn't complete the operation without waiting.
+    WouldBlock,
+    /// The buffers of a connection don't fit in the buffer budget of its context, see
+    /// `UtpContext::set_global_buffer_budget`.
+    BufferBudgetExceeded,
+    /// The underlying UDP socket failed.
+    Io(io::Error),
+    /// Any other error, described here.
     Other(String),
 }
 
-impl Error for SocketError {
-    fn description(&self) -> &str {
-        use self::SocketError::*;
+impl UtpError {
+    /// Returns the kind of the `io::Error` this error converts into.
+    pub fn kind(&self) -> ErrorKind {
+        use self::UtpError::*;
+        match *self {
+            ConnectionClosed |
+            NotConnected       => ErrorKind::NotConnected,
+            ConnectionReset    => ErrorKind::ConnectionReset,
+            ConnectionTimedOut |
+            OperationTimedOut  => ErrorKind::TimedOut,
+            InvalidAddress |
+            InvalidInput(_) |
+            PacketTooLarge     => ErrorKind::InvalidInput,
+            InvalidPacket(_)   => ErrorKind::InvalidData,
+            InvalidReply       => ErrorKind::ConnectionRefused,
+            Interrupted        => ErrorKind::Interrupted,
+            WouldBlock         => ErrorKind::WouldBlock,
+            BufferBudgetExceeded => ErrorKind::OutOfMemory,
+            Io(ref e)          => e.kind(),
+            Other(_)           => ErrorKind::Other,
+        }
+    }
+}
+
+impl Error for UtpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
         match *self {
+            UtpError::InvalidPacket(ref e) => Some(e),
+            UtpError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for UtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::UtpError::*;
+        let message = match *self {
             ConnectionClosed   => "The socket is closed",
             ConnectionReset    => "Connection reset by remote peer",
             ConnectionTimedOut => "Connection timed out",
             InvalidAddress     => "Invalid address",
             InvalidReply       => "The remote peer sent an invalid reply",
+            Interrupted        => "The operation was cancelled",
             NotConnected       => "The socket is not connected",
+            OperationTimedOut  => "The operation timed out",
+            PacketTooLarge     => "The packet is too large",
+            WouldBlock         => "The operation would block",
+            BufferBudgetExceeded => "The buffer budget is exhausted",
+            InvalidInput(s) => s,
             Other(ref s) => s,
-        }
+            InvalidPacket(ref e) => return write!(f, "Invalid packet: {}", e),
+            Io(ref e) => return e.fmt(f),
+        };
+        f.write_str(message)
     }
 }
 
-impl fmt::Display for SocketError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str(self.description())
+impl From<io::Error> for UtpError {
+    /// Wraps an I/O error, unless it was converted from a `UtpError`, which is returned instead.
+    fn from(error: io::Error) -> UtpError {
+        let converted = error.get_ref().is_some_and(|e| e.is::<UtpError>());
+        if converted {
+            let inner = error.into_inner().expect("The error has no inner error");
+            *inner.downcast::<UtpError>().expect("The inner error isn't a UtpError")
+        } else {
+            UtpError::Io(error)
+        }
     }
 }
 
-impl From<SocketError> for io::Error {
-    fn from(error: SocketError) -> io::Error {
-        use self::SocketError::*;
-        let kind = match error {
-            ConnectionClosed |
-            NotConnected       => ErrorKind::NotConnected,
-            ConnectionReset    => ErrorKind::ConnectionReset,
-            ConnectionTimedOut => ErrorKind::TimedOut,
-            InvalidAddress     => ErrorKind::InvalidInput,
-            InvalidReply       => ErrorKind::ConnectionRefused,
-            Other(_)           => ErrorKind::Other,
-        };
-        io::Error::new(kind, error.description())
+impl From<ParseError> for UtpError {
+    fn from(error: ParseError) -> UtpError {
+        UtpError::InvalidPacket(error)
     }
 }
 
-#[derive(Debug)]
-pub enum ParseError {
-    InvalidExtensionLength,
-    InvalidPacketLength,
-    InvalidPacketType(u8),
-    UnsupportedVersion,
-}
-
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
+impl From<UtpError> for io::Error {
+    fn from(error: UtpError) -> io::Error {
+        match error {
+            UtpError::Io(e) => e,
+            error => io::Error::new(error.kind(), error),
+        }
     }
 }
 
-impl Error for ParseError {
-    fn description(&self) -> &str {
-        use self::ParseError::*;
-        match *self {
-            InvalidExtensionLength => "Invalid extension length (must be a non-zero multiple of 4)",
-            InvalidPacketLength => "The packet is too small",
-            InvalidPacketType(_) => "Invalid packet type",
-            UnsupportedVersion => "Unsupported packet version",
+#[cfg(test)]
+mod test {
+    use crate::error::UtpError;
+    use crate::packet::ParseError;
+    use std::io::{self, ErrorKind};
+
+    #[test]
+    fn test_io_error_round_trip() {
+        let error = io::Error::from(UtpError::ConnectionReset);
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        match UtpError::from(error) {
+            UtpError::ConnectionReset => (),
+            x => panic!("Expected ConnectionReset, got {:?}", x),
         }
-    }
-}
 
-impl From<ParseError> for io::Error {
-    fn from(error: ParseError) -> io::Error {
-        io::Error::new(ErrorKind::Other, error.description())
+        let error = io::Error::from(UtpError::InvalidPacket(ParseError::UnsupportedVersion(2)));
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "Invalid packet: Unsupported packet version 2");
+
+        // Other I/O errors are wrapped, and unwrapped when converted back
+        let error = UtpError::from(io::Error::new(ErrorKind::AddrInUse, "in use"));
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        let error = io::Error::from(error);
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        assert_eq!(error.to_string(), "in use");
     }
 }
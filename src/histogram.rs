@@ -0,0 +1,163 @@
+use std::cmp::{min, max};
+use std::time::Duration;
+
+/// Number of buckets of a `Histogram`.
+const BUCKETS: usize = 16;
+
+/// A histogram of durations, such as round-trip times, in a fixed number of buckets.
+///
+/// The first bucket holds the samples below 1 ms, then each bucket covers twice the range of the
+/// previous one: from 1 ms to 2 ms, from 2 ms to 4 ms, and so on, up to the last bucket holding the
+/// samples above 16.384 s. Memory use doesn't grow with the number of samples, at the cost of
+/// percentiles only being known up to their bucket.
+///
+/// Telling steady high latency (samples packed in a few buckets) from bursty jitter (samples
+/// spread over many) is what it's meant for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+/// Returns the lower bound of the samples of bucket `index`.
+fn lower_bound(index: usize) -> Duration {
+    match index {
+        0 => Duration::from_millis(0),
+        _ => Duration::from_millis(1 << (index - 1)),
+    }
+}
+
+impl Histogram {
+    /// Creates an empty histogram.
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    /// Adds a sample.
+    pub fn record(&mut self, sample: Duration) {
+        let millis = sample.as_millis();
+        let index = match millis {
+            0 => 0,
+            _ => min(128 - millis.leading_zeros() as usize, BUCKETS - 1),
+        };
+        self.counts[index] += 1;
+
+        if self.count == 0 {
+            self.min = sample;
+            self.max = sample;
+        } else {
+            self.min = min(self.min, sample);
+            self.max = max(self.max, sample);
+        }
+        self.count += 1;
+        self.total += sample;
+    }
+
+    /// Returns the number of samples.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the smallest sample, if any.
+    pub fn min(&self) -> Option<Duration> {
+        if self.count > 0 { Some(self.min) } else { None }
+    }
+
+    /// Returns the largest sample, if any.
+    pub fn max(&self) -> Option<Duration> {
+        if self.count > 0 { Some(self.max) } else { None }
+    }
+
+    /// Returns the average of the samples, if any.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.count > 0 {
+            Some(Duration::from_nanos((self.total.as_nanos() / u128::from(self.count)) as u64))
+        } else {
+            None
+        }
+    }
+
+    /// Returns an upper bound of the given percentile of the samples, if any.
+    ///
+    /// That's the upper bound of the bucket the percentile falls in, or the largest sample if it's
+    /// smaller. `percentile` is clamped between 0 and 100.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let percentile = percentile.clamp(0.0, 100.0);
+        let rank = max((percentile / 100.0 * self.count as f64).ceil() as u64, 1);
+
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = match index {
+                    i if i == BUCKETS - 1 => self.max,
+                    i => lower_bound(i + 1),
+                };
+                return Some(max(min(bound, self.max), self.min));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Returns the lower bound and the number of samples of each bucket, in increasing order.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.counts.iter().enumerate().map(|(index, &count)| (lower_bound(index), count)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::histogram::Histogram;
+    use std::time::Duration;
+
+    #[test]
+    fn test_empty_histogram() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.min(), None);
+        assert_eq!(histogram.max(), None);
+        assert_eq!(histogram.mean(), None);
+        assert_eq!(histogram.percentile(50.0), None);
+        assert!(histogram.buckets().iter().all(|&(_, count)| count == 0));
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let mut histogram = Histogram::new();
+        for &millis in &[0, 1, 3, 3, 100, 20_000] {
+            histogram.record(Duration::from_millis(millis));
+        }
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), 16);
+        assert_eq!(buckets[0], (Duration::from_millis(0), 1));
+        assert_eq!(buckets[1], (Duration::from_millis(1), 1));
+        assert_eq!(buckets[2], (Duration::from_millis(2), 2));
+        assert_eq!(buckets[7], (Duration::from_millis(64), 1));
+        assert_eq!(buckets[15], (Duration::from_millis(16_384), 1));
+        assert_eq!(buckets.iter().map(|&(_, count)| count).sum::<u64>(), 6);
+    }
+
+    #[test]
+    fn test_histogram_summary() {
+        let mut histogram = Histogram::new();
+        for millis in 1..101 {
+            histogram.record(Duration::from_millis(millis));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.min(), Some(Duration::from_millis(1)));
+        assert_eq!(histogram.max(), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(50_500)));
+
+        // Percentiles are rounded up to the end of their bucket, but never beyond the samples
+        assert_eq!(histogram.percentile(50.0), Some(Duration::from_millis(64)));
+        assert_eq!(histogram.percentile(99.0), Some(Duration::from_millis(100)));
+        assert_eq!(histogram.percentile(0.0), Some(Duration::from_millis(2)));
+        assert_eq!(histogram.percentile(200.0), Some(Duration::from_millis(100)));
+    }
+}
@@ -45,7 +45,43 @@
 //! let socket = UtpSocket::bind("0.0.0.0:0").expect("Error binding socket");
 //! let stream: UtpStream = socket.into();
 //! ```
+//!
+//! # Asynchronous streams
+//!
+//! With the `tokio` feature, `AsyncUtpStream` implements the `AsyncRead` and `AsyncWrite` traits
+//! of [tokio][tokio], and `AsyncUtpListener` accepts connections as such streams. They run on the
+//! tokio runtime, timers included, instead of blocking a thread.
+//!
+//! [tokio]: https://crates.io/crates/tokio
+//!
+//! # Logging
+//!
+//! With the `log` feature, enabled by default, connections report their events through the
+//! [`log`][log] crate: state changes (such as connections opening and closing) at the `info`
+//! level, retransmissions and dropped packets at the `debug` level, and congestion window changes
+//! at the `trace` level. Each message starts with the connection identifier, in brackets, to tell
+//! concurrent connections apart. Without the feature, logging compiles to nothing.
+//!
+//! [log]: https://crates.io/crates/log
+//!
+//! # Event loops
+//!
+//! A non-blocking `UtpSocket` can be driven from an event loop, by waiting for its underlying UDP
+//! socket to become readable for at most `UtpSocket::next_timeout`. With the `mio` feature, on
+//! Unix, `UtpSocket` implements [mio][mio]'s `Source`, to be registered with a `mio::Poll`
+//! directly; see the `mio-echo` example.
+//!
+//! [mio]: https://crates.io/crates/mio
+//!
+//! # Without the standard library
+//!
+//! Sockets, streams and listeners need the standard library, through the `std` feature enabled by
+//! default. Without it, the crate builds with `no_std`, needing only an allocator, and provides
+//! the packet encoding and decoding (`Packet`, `PacketHeader`, `PacketType`, `Extension`,
+//! `ExtensionType` and `ParseError`) and the congestion controllers (`CongestionController`,
+//! `Ledbat` and `FixedWindow`), for the building blocks of uTP over other datagram transports.
 
+#![cfg_attr(not(feature = "std"), no_std)]
 #![deny(missing_docs)]
 
 // Optional features
@@ -53,22 +89,44 @@
 #![cfg_attr(feature = "clippy", plugin(clippy))]
 #![cfg_attr(feature = "clippy", allow(len_without_is_empty, doc_markdown, needless_return,
                                       transmute_ptr_to_ref))]
-#![cfg_attr(feature = "unstable", feature(test))]
+#![cfg_attr(feature = "unstable", feature(test, read_buf, core_io_borrowed_buf))]
 
-extern crate rand;
-extern crate num_traits;
-#[macro_use] extern crate log;
+#[cfg(feature = "std")] extern crate core;
+extern crate alloc;
+#[cfg(feature = "std")] extern crate rand;
+#[cfg(feature = "std")] extern crate num_traits;
+#[cfg(unix)] extern crate libc;
+#[cfg(feature = "log")] #[macro_use] extern crate log;
+#[cfg(not(feature = "log"))] #[macro_use] mod logging;
+#[cfg(feature = "serde")] extern crate serde;
+#[cfg(all(feature = "mio", unix))] extern crate mio;
+#[cfg(feature = "tokio")] extern crate tokio;
 #[cfg(test)] extern crate quickcheck;
+#[cfg(all(test, feature = "serde"))] extern crate serde_json;
 
 // Public API
-pub use socket::UtpSocket;
-pub use socket::UtpListener;
-pub use stream::UtpStream;
+#[cfg(feature = "std")] pub use socket::UtpSocket;
+#[cfg(feature = "std")]
+pub use socket::{UtpListener, Incoming, UtpContext, UtpSocketBuilder, ConnectionStats, SocketState};
+#[cfg(feature = "std")]
+pub use socket::{Backoff, Canceller, CloseReason, CongestionEvent, CongestionHints, ContextStats};
+#[cfg(feature = "std")] pub use socket::{Direction, PacketHandle, PacketTap, SendLimit};
+#[cfg(feature = "std")] pub use stream::{UtpStream, OwnedReadHalf, OwnedWriteHalf};
+pub use congestion::{CongestionController, Ledbat, FixedWindow};
+#[cfg(feature = "tokio")] pub use async_stream::{AsyncUtpListener, AsyncUtpStream};
+#[cfg(feature = "std")] pub use histogram::Histogram;
+pub use packet::{Packet, PacketHeader, PacketType, Extension, ExtensionType, ParseError};
+#[cfg(feature = "std")] pub use error::UtpError;
+#[cfg(feature = "std")] pub use transport::DatagramTransport;
 
+#[cfg(feature = "tokio")] mod async_stream;
 mod bit_iterator;
-mod error;
+mod congestion;
+#[cfg(feature = "std")] mod error;
+#[cfg(feature = "std")] mod histogram;
 mod packet;
-mod socket;
-mod stream;
+#[cfg(feature = "std")] mod socket;
+#[cfg(feature = "std")] mod stream;
 mod time;
-mod util;
+#[cfg(feature = "std")] mod transport;
+#[cfg(feature = "std")] mod util;
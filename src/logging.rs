@@ -0,0 +1,20 @@
+//! Stand-ins for the `log` macros when the `log` feature is disabled.
+//!
+//! They expand to code that is never run, so that the arguments are still type-checked (and
+//! count as used) without any logging code making it into the binary.
+
+// Some levels may only be used by code gated on the `log` feature
+#![allow(unused_macros)]
+
+macro_rules! log_nothing {
+    ($($arg:tt)*) => ({
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    })
+}
+
+macro_rules! trace { ($($arg:tt)*) => (log_nothing!($($arg)*)) }
+macro_rules! debug { ($($arg:tt)*) => (log_nothing!($($arg)*)) }
+macro_rules! info { ($($arg:tt)*) => (log_nothing!($($arg)*)) }
+macro_rules! warn { ($($arg:tt)*) => (log_nothing!($($arg)*)) }
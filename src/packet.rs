@@ -1,32 +1,66 @@
 #![allow(dead_code)]
 
-use bit_iterator::BitIterator;
-use error::ParseError;
-use std::fmt;
-use time::{Timestamp, Delay};
+#[cfg(not(feature = "std"))] use alloc::{borrow::ToOwned, vec::Vec};
+use crate::bit_iterator::BitIterator;
+use core::error::Error;
+use core::fmt;
+use crate::time::{Timestamp, Delay};
+#[cfg(feature = "serde")] use serde::{Serialize, Deserialize};
 
 pub const HEADER_SIZE: usize = 20;
 
-macro_rules! u8_to_unsigned_be {
-    ($src:ident, $start:expr, $end:expr, $t:ty) => ({
-        (0 .. $end - $start + 1).rev().fold(0, |acc, i| acc | $src[$start+i] as $t << (i * 8))
+// Protocol version of the packets written, in the low nibble of their first byte
+const VERSION: u8 = 1;
+
+// Largest SACK bitmask (in bytes) whose length fits in an extension header, as it must be a
+// multiple of 4
+const MAX_SACK_LEN: usize = 252;
+
+// Number of packets that must be acknowledged past a missing one for it to be considered lost
+const SACK_LOSS_THRESHOLD: u32 = 3;
+
+// Number of payload bytes shown when debug-formatting a packet
+const DEBUG_PAYLOAD_LEN: usize = 16;
+
+// Kind of the checksum extension. It isn't part of BEP 29, and other implementations use kinds 2
+// and 3, so peers that don't know about it skip it like any unknown extension.
+const CHECKSUM_EXTENSION: u8 = 5;
+
+/// Number of bytes the checksum extension adds to a packet.
+pub const CHECKSUM_EXTENSION_SIZE: usize = 6;
+
+// Reads a big-endian integer at the given offset of a byte slice
+macro_rules! read_be {
+    ($src:expr, $offset:expr, $t:ty) => ({
+        const LEN: usize = ::core::mem::size_of::<$t>();
+        let mut bytes = [0; LEN];
+        bytes.copy_from_slice(&$src[$offset..$offset + LEN]);
+        <$t>::from_be_bytes(bytes)
+    })
+}
+
+// Writes a big-endian integer at the given offset of a byte slice
+macro_rules! write_be {
+    ($dst:expr, $offset:expr, $value:expr) => ({
+        let bytes = $value.to_be_bytes();
+        $dst[$offset..$offset + bytes.len()].copy_from_slice(&bytes);
     })
 }
 
 macro_rules! make_getter {
-    ($name:ident, $t:ty, $m:ident) => {
+    ($(#[$attr:meta])* $name:ident, $t:ty, $offset:expr) => {
+        $(#[$attr])*
         pub fn $name(&self) -> $t {
-            let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-            $m::from_be(header.$name)
+            read_be!(self.0, $offset, $t)
         }
     }
 }
 
 macro_rules! make_setter {
-    ($fn_name:ident, $field:ident, $t: ty) => {
+    ($(#[$attr:meta])* $fn_name:ident, $t:ty, $offset:expr) => {
+        $(#[$attr])*
         pub fn $fn_name(&mut self, new: $t) {
-            let mut header = unsafe { &mut*(self.0.as_mut_ptr() as *mut PacketHeader) };
-            header.$field = new.to_be();
+            write_be!(self.0, $offset, new);
         }
     }
 }
@@ -36,16 +70,60 @@ macro_rules! make_setter {
 /// Waiting for rust-lang/rust#33417 to become stable.
 pub trait TryFrom<T>: Sized {
     type Err;
-    fn try_from(T) -> Result<Self, Self::Err>;
+    fn try_from(_: T) -> Result<Self, Self::Err>;
 }
 
+/// The reason a datagram couldn't be decoded as a uTP packet.
+///
+/// Sockets discard malformed packets from connected peers. When a malformed packet ends an
+/// operation instead, such as the reply to a connection request, the operation fails with
+/// `UtpError::InvalidPacket`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// A selective acknowledgement extension is empty. Other extensions may have any length.
+    InvalidExtensionLength,
+    /// The datagram is shorter than a packet header.
+    InvalidPacketLength,
+    /// The packet type, given here, is unknown.
+    InvalidPacketType(u8),
+    /// The datagram ends before the extension chain does.
+    TruncatedExtension,
+    /// The packet uses a protocol version, given here, other than 1.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseError::*;
+        match *self {
+            InvalidExtensionLength => {
+                f.write_str("Invalid extension length (a SACK bitmask can't be empty)")
+            }
+            InvalidPacketLength => write!(f, "The packet is too small (less than {} bytes)",
+                                          HEADER_SIZE),
+            InvalidPacketType(ty) => write!(f, "Invalid packet type {}", ty),
+            TruncatedExtension => f.write_str("The packet ends in the middle of an extension"),
+            UnsupportedVersion(version) => write!(f, "Unsupported packet version {}", version),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// The type of a uTP packet.
 #[derive(PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PacketType {
-    Data,  // packet carries a data payload
-    Fin,   // signals the end of a connection
-    State, // signals acknowledgment of a packet
-    Reset, // forcibly terminates a connection
-    Syn,   // initiates a new connection with a peer
+    /// Carries a data payload.
+    Data,
+    /// Signals the end of a connection.
+    Fin,
+    /// Signals acknowledgment of a packet.
+    State,
+    /// Forcibly terminates a connection.
+    Reset,
+    /// Initiates a new connection with a peer.
+    Syn,
 }
 
 impl TryFrom<u8> for PacketType {
@@ -75,9 +153,16 @@ impl From<PacketType> for u8 {
 }
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// The kind of a header extension.
 pub enum ExtensionType {
+    /// No extension, ending the chain.
     None,
+    /// A selective acknowledgement, see `Packet::set_sack`.
     SelectiveAck,
+    /// A CRC-32C of the payload, see `Packet::set_checksum`.
+    Checksum,
+    /// A kind this implementation doesn't know, skipped over when parsing.
     Unknown(u8),
 }
 
@@ -86,6 +171,7 @@ impl From<u8> for ExtensionType {
         match original {
             0 => ExtensionType::None,
             1 => ExtensionType::SelectiveAck,
+            CHECKSUM_EXTENSION => ExtensionType::Checksum,
             n => ExtensionType::Unknown(n),
         }
     }
@@ -96,33 +182,163 @@ impl From<ExtensionType> for u8 {
         match original {
             ExtensionType::None => 0,
             ExtensionType::SelectiveAck => 1,
+            ExtensionType::Checksum => CHECKSUM_EXTENSION,
             ExtensionType::Unknown(n) => n,
         }
     }
 }
 
-#[derive(Clone)]
-pub struct Extension<'a> {
-    ty: ExtensionType,
-    pub data: &'a [u8],
+/// A header extension, one link of the chain following the packet header.
+///
+/// On the wire, each extension is preceded by the kind of the next one (0 ending the chain) and
+/// its own length. The kind of the first extension is stored in the packet header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Extension {
+    kind: u8,
+    /// The content of the extension, following its length on the wire.
+    pub data: Vec<u8>,
 }
 
-impl<'a> Extension<'a> {
+impl Extension {
+    /// Creates an extension of the given kind, carrying `data`.
+    pub fn new(kind: u8, data: Vec<u8>) -> Extension {
+        Extension { kind, data }
+    }
+
+    /// Returns the raw kind of the extension, as sent on the wire.
+    pub fn kind(&self) -> u8 {
+        self.kind
+    }
+
+    /// Returns the length of the extension's content, in bytes.
     pub fn len(&self) -> usize {
         self.data.len()
     }
 
+    /// Returns whether the extension's content is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns the kind of the extension.
     pub fn get_type(&self) -> ExtensionType {
-        self.ty
+        self.kind.into()
+    }
+
+    /// Iterates over the bits of the extension's content, starting with the least significant
+    /// bit of each byte.
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = bool> + '_ {
+        BitIterator::from_bytes(&self.data)
+    }
+}
+
+/// A selective acknowledgement of packets received out of order, as carried by a SACK extension.
+///
+/// The packet following `ack_nr` is implicitly missing, so the first bit of the bitmask refers to
+/// the packet with sequence number `ack_nr + 2`. Each byte holds eight packets, starting with its
+/// least significant bit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectiveAck {
+    ack_nr: u16,
+    bitmask: Vec<u8>,
+}
+
+impl SelectiveAck {
+    /// Builds a selective acknowledgement of the `received` sequence numbers, relative to the
+    /// cumulative acknowledgement number `ack_nr`.
+    ///
+    /// Sequence numbers not following `ack_nr + 1` closely enough to fit in the bitmask are
+    /// ignored. Returns `None` if there is nothing to acknowledge.
+    pub fn new<I: IntoIterator<Item = u16>>(ack_nr: u16, received: I) -> Option<SelectiveAck> {
+        let mut bitmask = Vec::new();
+
+        for seq_nr in received {
+            let diff = seq_nr.wrapping_sub(ack_nr).wrapping_sub(2) as usize;
+            if diff >= MAX_SACK_LEN * 8 {
+                continue;
+            }
+
+            // Keep the bitmask length a multiple of 4
+            let (byte, bit) = (diff / 8, diff % 8);
+            while byte >= bitmask.len() {
+                bitmask.extend_from_slice(&[0; 4]);
+            }
+            bitmask[byte] |= 1 << bit;
+        }
+
+        if bitmask.is_empty() {
+            None
+        } else {
+            Some(SelectiveAck { ack_nr, bitmask })
+        }
+    }
+
+    /// Returns the cumulative acknowledgement number this acknowledgement is relative to.
+    pub fn ack_nr(&self) -> u16 {
+        self.ack_nr
+    }
+
+    /// Returns the raw bitmask, as sent in the extension.
+    pub fn bitmask(&self) -> &[u8] {
+        &self.bitmask
+    }
+
+    /// Returns the sequence numbers of the packets selectively acknowledged, in ascending order.
+    pub fn acked_packets(&self) -> Vec<u16> {
+        BitIterator::from_bytes(&self.bitmask)
+            .enumerate()
+            .filter(|&(_, received)| received)
+            .map(|(idx, _)| self.ack_nr.wrapping_add(2).wrapping_add(idx as u16))
+            .collect()
     }
 
-    pub fn iter(&self) -> BitIterator {
-        BitIterator::from_bytes(self.data)
+    /// Returns whether the packet with the given sequence number is selectively acknowledged.
+    pub fn is_acked(&self, seq_nr: u16) -> bool {
+        let diff = seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+        diff < self.bitmask.len() * 8 && self.bitmask[diff / 8] >> (diff % 8) & 0x1 == 0x1
+    }
+
+    /// Returns the sequence numbers of the packets that should be considered lost, in ascending
+    /// order.
+    ///
+    /// A packet is lost if it wasn't acknowledged but at least three packets sent after it were.
+    /// Packets that are simply still in flight (with fewer acknowledged packets after them) are
+    /// not considered lost.
+    pub fn lost_packets(&self) -> Vec<u16> {
+        let mut acked_after = BitIterator::from_bytes(&self.bitmask).count_ones();
+        let mut lost = Vec::new();
+
+        if acked_after >= SACK_LOSS_THRESHOLD {
+            lost.push(self.ack_nr.wrapping_add(1));
+        }
+
+        for (idx, received) in BitIterator::from_bytes(&self.bitmask).enumerate() {
+            if received {
+                acked_after -= 1;
+            } else if acked_after >= SACK_LOSS_THRESHOLD {
+                lost.push(self.ack_nr.wrapping_add(2).wrapping_add(idx as u16));
+            } else {
+                break;
+            }
+        }
+
+        lost
+    }
+}
+
+impl From<SelectiveAck> for Vec<u8> {
+    fn from(sack: SelectiveAck) -> Vec<u8> {
+        sack.bitmask
     }
 }
 
-#[repr(C)]
-struct PacketHeader {
+/// A decoded packet header.
+///
+/// On the wire, fields are laid out in this order, in network (big-endian) byte order, for a total
+/// of 20 bytes.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PacketHeader {
     type_ver: u8, // type: u4, ver: u4
     extension: u8,
     connection_id: u16,
@@ -155,53 +371,86 @@ impl PacketHeader {
     pub fn get_extension_type(&self) -> ExtensionType {
         self.extension.into()
     }
-}
 
-impl AsRef<[u8]> for PacketHeader {
-    /// Returns the packet header as a slice of bytes.
-    fn as_ref(&self) -> &[u8] {
-        unsafe { &*(self as *const PacketHeader as *const [u8; HEADER_SIZE]) }
+    /// Encodes the header, with its fields in network (big-endian) byte order.
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[0] = self.type_ver;
+        buf[1] = self.extension;
+        write_be!(buf, 2, self.connection_id);
+        write_be!(buf, 4, self.timestamp);
+        write_be!(buf, 8, self.timestamp_difference);
+        write_be!(buf, 12, self.wnd_size);
+        write_be!(buf, 16, self.seq_nr);
+        write_be!(buf, 18, self.ack_nr);
+        buf
     }
-}
 
-impl<'a> TryFrom<&'a [u8]> for PacketHeader {
-    type Err = ParseError;
-    /// Reads a byte buffer and returns the corresponding packet header.
-    /// It assumes the fields are in network (big-endian) byte order,
-    /// preserving it.
-    fn try_from(buf: &[u8]) -> Result<Self, Self::Err> {
+    /// Reads a whole packet from a byte buffer and returns its header, checking that the rest of
+    /// the packet is well-formed.
+    ///
+    /// The buffer must hold at least a header with a supported version and a known packet type,
+    /// followed by a complete chain of extensions. Anything else yields an error, so any datagram
+    /// can be safely decoded.
+    ///
+    /// The fields are read in network (big-endian) byte order, as written by `to_bytes`.
+    pub fn decode(buf: &[u8]) -> Result<PacketHeader, ParseError> {
         // Check length
         if buf.len() < HEADER_SIZE {
             return Err(ParseError::InvalidPacketLength);
         }
 
-        // Check version
-        if buf[0] & 0x0F != 1 {
-            return Err(ParseError::UnsupportedVersion);
-        }
+        check_version(buf[0] & 0x0F)?;
 
         // Check packet type
-        if let Err(e) = PacketType::try_from(buf[0] >> 4) {
-            return Err(e);
-        }
+        PacketType::try_from(buf[0] >> 4)?;
+
+        // Check extensions, so they can later be walked without bounds checks
+        check_extensions(buf)?;
 
         Ok(PacketHeader {
             type_ver: buf[0],
             extension: buf[1],
-            connection_id: u8_to_unsigned_be!(buf, 2, 3, u16),
-            timestamp: u8_to_unsigned_be!(buf, 4, 7, u32),
-            timestamp_difference: u8_to_unsigned_be!(buf, 8, 11, u32),
-            wnd_size: u8_to_unsigned_be!(buf, 12, 15, u32),
-            seq_nr: u8_to_unsigned_be!(buf, 16, 17, u16),
-            ack_nr: u8_to_unsigned_be!(buf, 18, 19, u16),
+            connection_id: read_be!(buf, 2, u16),
+            timestamp: read_be!(buf, 4, u32),
+            timestamp_difference: read_be!(buf, 8, u32),
+            wnd_size: read_be!(buf, 12, u32),
+            seq_nr: read_be!(buf, 16, u16),
+            ack_nr: read_be!(buf, 18, u16),
         })
     }
 }
 
+impl TryFrom<&[u8]> for PacketHeader {
+    type Err = ParseError;
+    /// Reads a byte buffer and returns the corresponding packet header.
+    ///
+    /// See `PacketHeader::decode` for details.
+    fn try_from(buf: &[u8]) -> Result<Self, Self::Err> {
+        PacketHeader::decode(buf)
+    }
+}
+
+impl fmt::Debug for PacketHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PacketHeader")
+         .field("type", &self.get_type())
+         .field("version", &self.get_version())
+         .field("extension", &self.get_extension_type())
+         .field("connection_id", &self.connection_id)
+         .field("timestamp", &self.timestamp)
+         .field("timestamp_difference", &self.timestamp_difference)
+         .field("wnd_size", &self.wnd_size)
+         .field("seq_nr", &self.seq_nr)
+         .field("ack_nr", &self.ack_nr)
+         .finish()
+    }
+}
+
 impl Default for PacketHeader {
     fn default() -> PacketHeader {
         PacketHeader {
-            type_ver: u8::from(PacketType::Data) << 4 | 1,
+            type_ver: u8::from(PacketType::Data) << 4 | VERSION,
             extension: 0,
             connection_id: 0,
             timestamp: 0,
@@ -213,6 +462,8 @@ impl Default for PacketHeader {
     }
 }
 
+/// A packet, stored as it is sent on the wire.
+#[derive(PartialEq, Eq)]
 pub struct Packet(Vec<u8>);
 
 impl AsRef<[u8]> for Packet {
@@ -224,7 +475,7 @@ impl AsRef<[u8]> for Packet {
 impl Packet {
     /// Constructs a new, empty packet.
     pub fn new() -> Packet {
-        Packet(PacketHeader::default().as_ref().to_owned())
+        Packet(PacketHeader::default().to_bytes().to_vec())
     }
 
     /// Constructs a new data packet with the given payload.
@@ -232,41 +483,124 @@ impl Packet {
         let mut inner = Vec::with_capacity(HEADER_SIZE + payload.len());
         let mut header = PacketHeader::default();
         header.set_type(PacketType::Data);
-        // inner.copy_from_slice(header.as_ref());
-        // inner.copy_from_slice(payload);
-        inner.extend_from_slice(header.as_ref());
+        inner.extend_from_slice(&header.to_bytes());
         inner.extend_from_slice(payload);
 
         Packet(inner)
     }
 
+    /// Returns a decoded copy of the packet's header.
+    pub fn header(&self) -> PacketHeader {
+        PacketHeader {
+            type_ver: self.0[0],
+            extension: self.0[1],
+            connection_id: self.connection_id(),
+            timestamp: read_be!(self.0, 4, u32),
+            timestamp_difference: read_be!(self.0, 8, u32),
+            wnd_size: self.wnd_size(),
+            seq_nr: self.seq_nr(),
+            ack_nr: self.ack_nr(),
+        }
+    }
+
+    /// Sets the type of packet to the specified type.
     #[inline]
     pub fn set_type(&mut self, t: PacketType) {
-        let mut header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.set_type(t);
+        let version = 0x0F & self.0[0];
+        self.0[0] = u8::from(t) << 4 | version;
     }
 
+    /// Returns the packet's type.
     #[inline]
     pub fn get_type(&self) -> PacketType {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_type()
+        // Packets are always built with a valid type, or decoded after checking it
+        PacketType::try_from(self.0[0] >> 4).unwrap()
     }
 
+    /// Returns the packet's version.
     pub fn get_version(&self) -> u8 {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_version()
+        self.0[0] & 0x0F
     }
 
+    /// Returns the type of the first extension.
     pub fn get_extension_type(&self) -> ExtensionType {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        header.get_extension_type()
+        self.0[1].into()
     }
 
-    pub fn extensions(&self) -> ExtensionIterator {
+    /// Returns an iterator over the packet's extensions, in the order they are chained.
+    pub fn extensions(&self) -> ExtensionIterator<'_> {
         ExtensionIterator::new(self)
     }
 
+    /// Returns the selective acknowledgement carried by the first SACK extension, if any.
+    pub fn selective_ack(&self) -> Option<SelectiveAck> {
+        self.extensions()
+            .find(|extension| extension.get_type() == ExtensionType::SelectiveAck)
+            .map(|extension| SelectiveAck {
+                ack_nr: self.ack_nr(),
+                bitmask: extension.data,
+            })
+    }
+
+    /// Returns the sequence numbers of the packets the first SACK extension acknowledges out of
+    /// order, in ascending order, or an empty vector if there is none.
+    pub fn selective_acks(&self) -> Vec<u16> {
+        self.selective_ack().map(|sack| sack.acked_packets()).unwrap_or_default()
+    }
+
+    /// Appends an extension to the end of the packet's extension chain, before the payload.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the extension's kind is 0, reserved for ending the chain, or if its length
+    /// doesn't fit in a byte. A SACK extension must also have a non-zero length, multiple of 4.
+    pub fn add_extension(&mut self, extension: Extension) {
+        assert!(extension.kind != 0, "Extension kind 0 is reserved");
+        assert!(extension.data.len() <= u8::MAX as usize);
+        if extension.get_type() == ExtensionType::SelectiveAck {
+            assert!(!extension.data.is_empty() && extension.data.len() <= MAX_SACK_LEN);
+            assert_eq!(extension.data.len() % 4, 0);
+        }
+
+        // Find the byte holding the kind of the extension following the last one
+        let mut link = 1;
+        let mut index = HEADER_SIZE;
+        while self.0[link] != 0 {
+            link = index;
+            index += self.0[index + 1] as usize + 2;
+        }
+
+        self.0[link] = extension.kind;
+        let len = extension.data.len() as u8;
+        self.0.splice(index..index, [0, len].iter().cloned().chain(extension.data));
+    }
+
+    /// Replaces the packet's extension chain, linking the given extensions in order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any extension is invalid, see `add_extension`.
+    pub fn set_extensions(&mut self, extensions: Vec<Extension>) {
+        let start = self.payload_start();
+        self.0.drain(HEADER_SIZE..start);
+        self.0[1] = 0;
+        for extension in extensions {
+            self.add_extension(extension);
+        }
+    }
+
+    /// Returns the packet's payload, following the extensions.
     pub fn payload(&self) -> &[u8] {
+        &self.0[self.payload_start()..]
+    }
+
+    /// Appends `data` to the packet's payload.
+    pub fn extend_payload(&mut self, data: &[u8]) {
+        self.0.extend_from_slice(data);
+    }
+
+    /// Returns the index of the first payload byte, right after the extension chain.
+    fn payload_start(&self) -> usize {
         let mut index = HEADER_SIZE;
         let mut extension_type = ExtensionType::from(self.0[1]);
 
@@ -275,104 +609,96 @@ impl Packet {
             let len = self.0[index + 1] as usize;
 
             // Assume extension is valid because the bytes come from a (valid) Packet
-            // ...
-
             extension_type = ExtensionType::from(self.0[index]);
             index += len + 2;
         }
 
-        &self.0[index..]
+        index
     }
 
+    /// Returns the time the packet was sent at, in microseconds of the sender's clock.
     pub fn timestamp(&self) -> Timestamp {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        u32::from_be(header.timestamp).into()
+        read_be!(self.0, 4, u32).into()
     }
 
+    /// Sets the time the packet is sent at.
     pub fn set_timestamp(&mut self, timestamp: Timestamp) {
-        let header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.timestamp = u32::from(timestamp).to_be();
+        write_be!(self.0, 4, u32::from(timestamp));
     }
 
+    /// Returns the one-way delay of the latest packet the sender received, as it measured it.
     pub fn timestamp_difference(&self) -> Delay {
-        let header = unsafe { &*(self.0.as_ptr() as *const PacketHeader) };
-        u32::from_be(header.timestamp_difference).into()
+        read_be!(self.0, 8, u32).into()
     }
 
+    /// Sets the one-way delay reported to the receiver.
     pub fn set_timestamp_difference(&mut self, delay: Delay) {
-        let header = unsafe { &mut *(self.0.as_mut_ptr() as *mut PacketHeader) };
-        header.timestamp_difference = u32::from(delay).to_be();
+        write_be!(self.0, 8, u32::from(delay));
     }
 
-    make_getter!(seq_nr, u16, u16);
-    make_getter!(ack_nr, u16, u16);
-    make_getter!(connection_id, u16, u16);
-    make_getter!(wnd_size, u32, u32);
-
-    make_setter!(set_seq_nr, seq_nr, u16);
-    make_setter!(set_ack_nr, ack_nr, u16);
-    make_setter!(set_connection_id, connection_id, u16);
-    make_setter!(set_wnd_size, wnd_size, u32);
+    make_getter!(/// Returns the identifier of the connection the packet belongs to.
+                 connection_id, u16, 2);
+    make_getter!(/// Returns the receive window the sender advertises, in bytes.
+                 wnd_size, u32, 12);
+    make_getter!(/// Returns the packet's sequence number.
+                 seq_nr, u16, 16);
+    make_getter!(/// Returns the sequence number of the latest packet the sender received in order.
+                 ack_nr, u16, 18);
+
+    make_setter!(/// Sets the identifier of the connection the packet belongs to.
+                 set_connection_id, u16, 2);
+    make_setter!(/// Sets the advertised receive window, in bytes.
+                 set_wnd_size, u32, 12);
+    make_setter!(/// Sets the packet's sequence number.
+                 set_seq_nr, u16, 16);
+    make_setter!(/// Sets the acknowledged sequence number.
+                 set_ack_nr, u16, 18);
 
     /// Sets Selective ACK field in packet header and adds appropriate data.
     ///
     /// The length of the SACK extension is expressed in bytes, which
     /// must be a multiple of 4 and at least 4.
     pub fn set_sack(&mut self, bv: Vec<u8>) {
-        // The length of the SACK extension is expressed in bytes, which
-        // must be a multiple of 4 and at least 4.
-        assert!(bv.len() >= 4);
-        assert_eq!(bv.len() % 4, 0);
-
-        let mut index = HEADER_SIZE;
-        let mut extension_type = ExtensionType::from(self.0[1]);
-
-        // Set extension type in header if none is used, otherwise find and update the
-        // "next extension type" marker in the last extension before payload
-        if extension_type == ExtensionType::None {
-            self.0[1] = ExtensionType::SelectiveAck.into();
-        } else {
-            // Skip over all extensions until last, then modify its "next extension type" field and
-            // add the new extension after it.
-
-            // Consume known extensions and skip over unknown ones
-            while index < self.0.len() && extension_type != ExtensionType::None {
-                let len = self.0[index + 1] as usize;
-                // No validity checks needed
-                // ...
+        self.add_extension(Extension::new(ExtensionType::SelectiveAck.into(), bv));
+    }
 
-                extension_type = ExtensionType::from(self.0[index]);
+    /// Returns the payload checksum carried by the first checksum extension, if any.
+    pub fn checksum(&self) -> Option<u32> {
+        self.extensions()
+            .find(|extension| extension.get_type() == ExtensionType::Checksum)
+            .filter(|extension| extension.len() == 4)
+            .map(|extension| read_be!(extension.data, 0, u32))
+    }
 
-                // Arrived at last extension
-                if extension_type == ExtensionType::None {
-                    // Mark existence of an additional extension
-                    self.0[index] = ExtensionType::SelectiveAck.into();
-                }
-                index += len + 2;
+    /// Sets the checksum extension, carrying `checksum` (the CRC-32C of the payload) for the
+    /// remote peer to tell corrupted payloads apart.
+    ///
+    /// The first checksum extension is overwritten if there is one, as when the payload changed
+    /// since it was computed, and one is added otherwise.
+    pub fn set_checksum(&mut self, checksum: u32) {
+        let mut kind = self.0[1];
+        let mut index = HEADER_SIZE;
+        while kind != 0 && index < self.0.len() {
+            let len = self.0[index + 1] as usize;
+            if kind == CHECKSUM_EXTENSION && len == 4 {
+                write_be!(self.0, index + 2, checksum);
+                return;
             }
+            kind = self.0[index];
+            index += len + 2;
         }
-
-        // Insert the new extension into the packet's data.
-        // The way this is currently done is potentially slower than the alternative of resizing the
-        // underlying Vec, moving the payload forward and then writing the extension in the "new"
-        // place before the payload.
-
-        // Set the type of the following (non-existent) extension
-        self.0.insert(index, ExtensionType::None.into());
-        // Set this extension's length
-        self.0.insert(index + 1, bv.len() as u8);
-        // Write this extension's data
-        for (i, &value) in bv.iter().enumerate() {
-            self.0.insert(index + 2 + i, value);
-        }
+        self.add_extension(Extension::new(CHECKSUM_EXTENSION, checksum.to_be_bytes().to_vec()));
     }
 
+    /// Returns the size of the packet on the wire, header and extensions included.
+    // A packet always holds at least its header, so it's never empty
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         self.0.len()
     }
 }
 
-impl<'a> TryFrom<&'a [u8]> for Packet {
+impl TryFrom<&[u8]> for Packet {
     type Err = ParseError;
 
     /// Decodes a byte slice and construct the equivalent Packet.
@@ -381,9 +707,13 @@ impl<'a> TryFrom<&'a [u8]> for Packet {
     /// all except the initial 20 bytes corresponding to the header as payload.
     /// It's the caller's responsibility to use an appropriately sized buffer.
     fn try_from(buf: &[u8]) -> Result<Self, Self::Err> {
-        PacketHeader::try_from(buf)
-            .and(check_extensions(buf))
-            .and(Ok(Packet(buf.to_owned())))
+        PacketHeader::decode(buf).map(|_| Packet(buf.to_owned()))
+    }
+}
+
+impl Default for Packet {
+    fn default() -> Packet {
+        Packet::new()
     }
 }
 
@@ -393,22 +723,51 @@ impl Clone for Packet {
     }
 }
 
+/// Shows the header fields, the kind and length of each extension, and the beginning of the
+/// payload.
 impl fmt::Debug for Packet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Packet")
          .field("type", &self.get_type())
          .field("version", &self.get_version())
-         .field("extension", &self.get_extension_type())
          .field("connection_id", &self.connection_id())
          .field("timestamp", &self.timestamp())
          .field("timestamp_difference", &self.timestamp_difference())
          .field("wnd_size", &self.wnd_size())
          .field("seq_nr", &self.seq_nr())
          .field("ack_nr", &self.ack_nr())
+         .field("extensions", &DebugExtensions(self))
+         .field("payload", &DebugPayload(self.payload()))
          .finish()
     }
 }
 
+// Lists the extensions of a packet, without their data
+struct DebugExtensions<'a>(&'a Packet);
+
+impl<'a> fmt::Debug for DebugExtensions<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for extension in self.0.extensions() {
+            list.entry(&format_args!("{:?} ({} bytes)", extension.get_type(), extension.len()));
+        }
+        list.finish()
+    }
+}
+
+// Shows the first `DEBUG_PAYLOAD_LEN` bytes of a payload, along with its length
+struct DebugPayload<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugPayload<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.len() > DEBUG_PAYLOAD_LEN {
+            write!(f, "{:?}... ({} bytes)", &self.0[..DEBUG_PAYLOAD_LEN], self.0.len())
+        } else {
+            write!(f, "{:?} ({} bytes)", self.0, self.0.len())
+        }
+    }
+}
+
 pub struct ExtensionIterator<'a> {
     raw_bytes: &'a [u8],
     next_extension: ExtensionType,
@@ -426,7 +785,7 @@ impl<'a> ExtensionIterator<'a> {
 }
 
 impl<'a> Iterator for ExtensionIterator<'a> {
-    type Item = Extension<'a>;
+    type Item = Extension;
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next_extension == ExtensionType::None {
@@ -438,8 +797,8 @@ impl<'a> Iterator for ExtensionIterator<'a> {
 
             // Assume extension is valid because the bytes come from a (valid) Packet
             let extension = Extension {
-                ty: self.next_extension,
-                data: &self.raw_bytes[extension_start..extension_end],
+                kind: self.next_extension.into(),
+                data: self.raw_bytes[extension_start..extension_end].to_vec(),
             };
 
             self.next_extension = self.raw_bytes[self.index].into();
@@ -452,6 +811,18 @@ impl<'a> Iterator for ExtensionIterator<'a> {
     }
 }
 
+/// Checks that a packet's protocol version is one this implementation speaks.
+///
+/// Packets of other versions, such as a future version 2, may lay out their header differently,
+/// so they're rejected rather than decoded as version 1 packets.
+fn check_version(version: u8) -> Result<(), ParseError> {
+    if version == VERSION {
+        Ok(())
+    } else {
+        Err(ParseError::UnsupportedVersion(version))
+    }
+}
+
 /// Validate correctness of packet extensions, if any, in byte slice
 fn check_extensions(data: &[u8]) -> Result<(), ParseError> {
     if data.len() < HEADER_SIZE {
@@ -461,45 +832,164 @@ fn check_extensions(data: &[u8]) -> Result<(), ParseError> {
     let mut index = HEADER_SIZE;
     let mut extension_type = ExtensionType::from(data[1]);
 
-    if data.len() == HEADER_SIZE && extension_type != ExtensionType::None {
-        return Err(ParseError::InvalidExtensionLength);
-    }
-
     // Consume known extensions and skip over unknown ones
     while index < data.len() && extension_type != ExtensionType::None {
         if data.len() < index + 2 {
-            return Err(ParseError::InvalidPacketLength);
+            return Err(ParseError::TruncatedExtension);
         }
         let len = data[index + 1] as usize;
         let extension_start = index + 2;
         let extension_end = extension_start + len;
 
-        // Check validity of extension length:
-        // - non-zero,
-        // - multiple of 4,
-        // - does not exceed packet length
-        if len == 0 || len % 4 != 0 || extension_end > data.len() {
+        // Check validity of extension length: SACK bitmasks can't be empty, though peers may not
+        // pad them to a multiple of 4, and no extension may exceed the packet length
+        if len == 0 && extension_type == ExtensionType::SelectiveAck {
             return Err(ParseError::InvalidExtensionLength);
         }
+        if extension_end > data.len() {
+            return Err(ParseError::TruncatedExtension);
+        }
 
         extension_type = ExtensionType::from(data[index]);
         index += len + 2;
     }
     // Check for pending extensions (early exit of previous loop)
     if extension_type != ExtensionType::None {
-        return Err(ParseError::InvalidPacketLength);
+        return Err(ParseError::TruncatedExtension);
     }
 
     Ok(())
 }
 
+/// Serialization of headers and packets in their decoded form, one field per header field, rather
+/// than as wire bytes.
+#[cfg(feature = "serde")]
+mod serde_impls {
+    #[cfg(not(feature = "std"))] use alloc::vec::Vec;
+    use crate::packet::{check_version, Extension, ExtensionType, Packet, PacketHeader, PacketType,
+                 ParseError, MAX_SACK_LEN};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    #[derive(Serialize, Deserialize)]
+    struct HeaderFields {
+        #[serde(rename = "type")]
+        packet_type: PacketType,
+        version: u8,
+        extension: ExtensionType,
+        connection_id: u16,
+        timestamp: u32,
+        timestamp_difference: u32,
+        wnd_size: u32,
+        seq_nr: u16,
+        ack_nr: u16,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct PacketFields {
+        #[serde(rename = "type")]
+        packet_type: PacketType,
+        version: u8,
+        connection_id: u16,
+        timestamp: u32,
+        timestamp_difference: u32,
+        wnd_size: u32,
+        seq_nr: u16,
+        ack_nr: u16,
+        extensions: Vec<Extension>,
+        payload: Vec<u8>,
+    }
+
+    impl Serialize for PacketHeader {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            HeaderFields {
+                packet_type: self.get_type(),
+                version: self.get_version(),
+                extension: self.get_extension_type(),
+                connection_id: self.connection_id,
+                timestamp: self.timestamp,
+                timestamp_difference: self.timestamp_difference,
+                wnd_size: self.wnd_size,
+                seq_nr: self.seq_nr,
+                ack_nr: self.ack_nr,
+            }.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for PacketHeader {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let fields = HeaderFields::deserialize(deserializer)?;
+            check_version(fields.version).map_err(D::Error::custom)?;
+            Ok(PacketHeader {
+                type_ver: u8::from(fields.packet_type) << 4 | fields.version,
+                extension: fields.extension.into(),
+                connection_id: fields.connection_id,
+                timestamp: fields.timestamp,
+                timestamp_difference: fields.timestamp_difference,
+                wnd_size: fields.wnd_size,
+                seq_nr: fields.seq_nr,
+                ack_nr: fields.ack_nr,
+            })
+        }
+    }
+
+    impl Serialize for Packet {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            PacketFields {
+                packet_type: self.get_type(),
+                version: self.get_version(),
+                connection_id: self.connection_id(),
+                timestamp: self.timestamp().into(),
+                timestamp_difference: self.timestamp_difference().into(),
+                wnd_size: self.wnd_size(),
+                seq_nr: self.seq_nr(),
+                ack_nr: self.ack_nr(),
+                extensions: self.extensions().collect(),
+                payload: self.payload().to_vec(),
+            }.serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Packet {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let fields = PacketFields::deserialize(deserializer)?;
+            check_version(fields.version).map_err(D::Error::custom)?;
+
+            let mut packet = Packet::new();
+            packet.set_type(fields.packet_type);
+            packet.set_connection_id(fields.connection_id);
+            packet.set_timestamp(fields.timestamp.into());
+            packet.set_timestamp_difference(fields.timestamp_difference.into());
+            packet.set_wnd_size(fields.wnd_size);
+            packet.set_seq_nr(fields.seq_nr);
+            packet.set_ack_nr(fields.ack_nr);
+
+            // Reject what `add_extension` would panic on
+            for extension in fields.extensions {
+                if extension.kind == 0 {
+                    return Err(D::Error::custom("extension kind 0 is reserved"));
+                }
+                let len = extension.len();
+                let sack = extension.get_type() == ExtensionType::SelectiveAck;
+                if len > u8::MAX as usize ||
+                   (sack && (len == 0 || len > MAX_SACK_LEN || !len.is_multiple_of(4))) {
+                    return Err(D::Error::custom(ParseError::InvalidExtensionLength));
+                }
+                packet.add_extension(extension);
+            }
+            packet.extend_payload(&fields.payload);
+            Ok(packet)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use packet::*;
-    use packet::{PacketHeader, check_extensions};
-    use packet::PacketType::{State, Data};
+    use crate::packet::*;
+    use crate::packet::PacketHeader;
+    use crate::packet::PacketType::{State, Data};
     use quickcheck::{QuickCheck, TestResult};
-    use time::*;
+    use crate::time::*;
 
     #[test]
     fn test_packet_decode() {
@@ -542,7 +1032,7 @@ mod tests {
         assert!(packet.payload().is_empty());
         let extensions: Vec<Extension> = packet.extensions().collect();
         assert_eq!(extensions.len(), 1);
-        assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+        assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
         assert_eq!(extensions[0].data, &[0, 0, 0, 0]);
         assert_eq!(extensions[0].len(), extensions[0].data.len());
         assert_eq!(extensions[0].len(), 4);
@@ -588,7 +1078,7 @@ mod tests {
                 // The invalid extension is discarded
                 let extensions: Vec<Extension> = packet.extensions().collect();
                 assert_eq!(extensions.len(), 2);
-                assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+                assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
                 assert_eq!(extensions[0].data, &[0, 0, 0, 0]);
                 assert_eq!(extensions[0].len(), extensions[0].data.len());
                 assert_eq!(extensions[0].len(), 4);
@@ -597,6 +1087,70 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_extension_chain() {
+        let extensions = vec![Extension::new(1, vec![0x01, 0, 0, 0]),
+                              Extension::new(0x7f, vec![1, 2, 3, 4, 5, 6, 7, 8]),
+                              Extension::new(2, vec![9, 10, 11, 12])];
+        let mut packet = Packet::with_payload(&[42]);
+        let original = packet.clone();
+        packet.set_extensions(extensions.clone());
+
+        // Each extension announces the kind of the next one, the last one ending the chain
+        let buf = packet.as_ref();
+        assert_eq!(buf[1], 1);
+        assert_eq!(&buf[HEADER_SIZE..HEADER_SIZE + 2], &[0x7f, 4]);
+        assert_eq!(&buf[HEADER_SIZE + 6..HEADER_SIZE + 8], &[2, 8]);
+        assert_eq!(&buf[HEADER_SIZE + 16..HEADER_SIZE + 18], &[0, 4]);
+        assert_eq!(buf.len(), HEADER_SIZE + 22 + 1);
+
+        let decoded = Packet::try_from(buf).unwrap();
+        assert_eq!(decoded.extensions().collect::<Vec<_>>(), extensions);
+        assert_eq!(decoded.extensions().nth(1).unwrap().get_type(), ExtensionType::Unknown(0x7f));
+        assert_eq!(decoded.selective_ack().unwrap().bitmask(), &[0x01, 0, 0, 0]);
+        assert_eq!(decoded.payload(), &[42]);
+
+        // Extensions can be appended to an existing chain, or removed altogether
+        packet.add_extension(Extension::new(3, vec![0; 4]));
+        assert_eq!(packet.extensions().count(), 4);
+        assert_eq!(packet.extensions().last().unwrap().kind(), 3);
+        assert_eq!(packet.payload(), &[42]);
+        packet.set_extensions(vec![]);
+        assert!(packet == original);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_add_extension_without_kind() {
+        Packet::new().add_extension(Extension::new(0, vec![0; 4]));
+    }
+
+    // Arbitrary extension chains survive encoding and decoding
+    #[test]
+    fn quicktest_extension_chain() {
+        fn run(chain: Vec<(u8, Vec<u8>)>, payload: Vec<u8>) -> TestResult {
+            let extensions: Vec<Extension> = chain.into_iter()
+                .map(|(kind, mut data)| {
+                    let len = (data.len() / 4 * 4).clamp(4, MAX_SACK_LEN);
+                    data.resize(len, 0xaa);
+                    Extension::new(kind.max(1), data)
+                })
+                .collect();
+            let mut packet = Packet::with_payload(&payload);
+            packet.set_extensions(extensions.clone());
+
+            match Packet::try_from(packet.as_ref()) {
+                Ok(decoded) => {
+                    TestResult::from_bool(decoded.extensions().collect::<Vec<_>>() == extensions &&
+                                          decoded.payload() == payload.as_slice())
+                }
+                Err(_) => TestResult::failed(),
+            }
+        }
+        QuickCheck::new().tests(1000)
+            .quickcheck(run as fn(Vec<(u8, Vec<u8>)>, Vec<u8>) -> TestResult)
+    }
+
     #[test]
     fn test_packet_set_type() {
         let mut packet = Packet::new();
@@ -620,7 +1174,7 @@ mod tests {
         {
             let extensions: Vec<Extension> = packet.extensions().collect();
             assert_eq!(extensions.len(), 1);
-            assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+            assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
             assert_eq!(extensions[0].data, &[1, 2, 3, 4]);
             assert_eq!(extensions[0].len(), extensions[0].data.len());
             assert_eq!(extensions[0].len(), 4);
@@ -631,16 +1185,154 @@ mod tests {
 
         let extensions: Vec<Extension> = packet.extensions().collect();
         assert_eq!(extensions.len(), 2);
-        assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+        assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
         assert_eq!(extensions[0].data, &[1, 2, 3, 4]);
         assert_eq!(extensions[0].len(), extensions[0].data.len());
         assert_eq!(extensions[0].len(), 4);
-        assert_eq!(extensions[1].ty, ExtensionType::SelectiveAck);
+        assert_eq!(extensions[1].get_type(), ExtensionType::SelectiveAck);
         assert_eq!(extensions[1].data, &[5, 6, 7, 8, 9, 10, 11, 12]);
         assert_eq!(extensions[1].len(), extensions[1].data.len());
         assert_eq!(extensions[1].len(), 8);
     }
 
+    #[test]
+    fn test_build_selective_ack() {
+        // Packet 11 is implicitly missing, so packet 12 is the first bit
+        let sack = SelectiveAck::new(10, vec![12, 14, 21, 10, 11]).unwrap();
+        assert_eq!(sack.bitmask(), &[0b0000_0101, 0b0000_0010, 0, 0]);
+        assert!(sack.is_acked(12));
+        assert!(!sack.is_acked(13));
+        assert!(sack.is_acked(21));
+        assert!(!sack.is_acked(11));
+        assert!(!sack.is_acked(10));
+
+        // The bitmask grows in multiples of 4 bytes
+        let sack = SelectiveAck::new(10, vec![12 + 32]).unwrap();
+        assert_eq!(sack.bitmask(), &[0, 0, 0, 0, 0b0000_0001, 0, 0, 0]);
+
+        // Nothing to acknowledge
+        assert_eq!(SelectiveAck::new(10, vec![9, 10, 11]), None);
+        assert_eq!(SelectiveAck::new(10, vec![]), None);
+    }
+
+    #[test]
+    fn test_selective_ack_wraps_around() {
+        let sack = SelectiveAck::new(u16::MAX - 1, vec![u16::MAX, 0, 2]).unwrap();
+        assert_eq!(sack.bitmask(), &[0b0000_0101, 0, 0, 0]);
+        assert!(sack.is_acked(0));
+        assert!(!sack.is_acked(1));
+        assert!(sack.is_acked(2));
+        assert!(!sack.is_acked(u16::MAX));
+        assert_eq!(sack.acked_packets(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_selective_ack_lost_packets() {
+        // 12, 14, 15 and 17 received: 11 and 13 have three or more packets acknowledged after
+        // them, 16 is still in flight
+        let sack = SelectiveAck::new(10, vec![12, 14, 15, 17]).unwrap();
+        assert_eq!(sack.lost_packets(), vec![11, 13]);
+
+        // Too few packets acknowledged to tell whether the missing ones are lost
+        let sack = SelectiveAck::new(10, vec![12, 14]).unwrap();
+        assert!(sack.lost_packets().is_empty());
+    }
+
+    #[test]
+    fn test_packet_selective_ack_round_trip() {
+        let mut packet = Packet::new();
+        packet.set_ack_nr(10);
+        assert_eq!(packet.selective_ack(), None);
+        assert!(packet.selective_acks().is_empty());
+
+        let sack = SelectiveAck::new(10, vec![12, 14]).unwrap();
+        packet.set_sack(sack.clone().into());
+
+        let bytes = packet.as_ref().to_owned();
+        let decoded = Packet::try_from(&bytes[..]).unwrap();
+        assert_eq!(decoded.selective_ack(), Some(sack));
+        assert_eq!(decoded.selective_acks(), vec![12, 14]);
+    }
+
+    #[test]
+    fn test_decode_extensions_of_any_length() {
+        // An unknown extension of 3 bytes, then a SACK bitmask of a single byte, as sent by peers
+        // that don't pad it to a multiple of 4
+        let buf = [0x21, 0x7f, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x00, 0x0a,
+                   0x01, 0x03, 0xaa, 0xbb, 0xcc,
+                   0x00, 0x01, 0b0000_0101,
+                   0x2a];
+        let packet = Packet::try_from(&buf).unwrap();
+        let extensions: Vec<Extension> = packet.extensions().collect();
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions[0].get_type(), ExtensionType::Unknown(0x7f));
+        assert_eq!(extensions[0].data, &[0xaa, 0xbb, 0xcc]);
+        assert_eq!(packet.selective_acks(), vec![12, 14]);
+        assert_eq!(packet.payload(), &[0x2a]);
+
+        // Extensions of any length are encoded as such, SACK bitmasks excepted
+        let mut packet = Packet::with_payload(&[0x2a]);
+        packet.add_extension(Extension::new(0x7f, vec![]));
+        packet.add_extension(Extension::new(0x7e, vec![1, 2, 3, 4, 5]));
+        let decoded = Packet::try_from(packet.as_ref()).unwrap();
+        assert_eq!(decoded.extensions().map(|e| e.len()).collect::<Vec<_>>(), vec![0, 5]);
+        assert_eq!(decoded.payload(), &[0x2a]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_add_unpadded_selective_ack() {
+        Packet::new().set_sack(vec![0; 3]);
+    }
+
+    #[test]
+    fn test_packet_len_counts_extensions() {
+        let mut packet = Packet::with_payload(b"Hello\n");
+        assert_eq!(packet.len(), HEADER_SIZE + 6);
+
+        // The extension header takes 2 bytes, followed by the bitmask
+        packet.set_sack(vec![0b1010_0000, 0, 0, 0]);
+        assert_eq!(packet.len(), HEADER_SIZE + 2 + 4 + 6);
+        assert_eq!(packet.len(), packet.as_ref().len());
+
+        let decoded = Packet::try_from(packet.as_ref()).unwrap();
+        assert_eq!(decoded.len(), packet.len());
+        assert_eq!(decoded.payload(), b"Hello\n");
+    }
+
+    #[test]
+    fn test_packet_checksum_round_trip() {
+        let mut packet = Packet::with_payload(b"Hello\n");
+        assert_eq!(packet.checksum(), None);
+
+        packet.set_sack(vec![0; 4]);
+        packet.set_checksum(0xdead_beef);
+        assert_eq!(packet.len(), HEADER_SIZE + 6 + CHECKSUM_EXTENSION_SIZE + 6);
+
+        let bytes = packet.as_ref().to_owned();
+        let decoded = Packet::try_from(&bytes[..]).unwrap();
+        assert_eq!(decoded.checksum(), Some(0xdead_beef));
+        assert_eq!(decoded.extensions().nth(1).unwrap().get_type(), ExtensionType::Checksum);
+        assert_eq!(decoded.payload(), b"Hello\n");
+
+        // Setting it again overwrites it
+        packet.set_checksum(0x0102_0304);
+        assert_eq!(packet.checksum(), Some(0x0102_0304));
+        assert_eq!(packet.extensions().count(), 2);
+        assert_eq!(packet.len(), HEADER_SIZE + 6 + CHECKSUM_EXTENSION_SIZE + 6);
+    }
+
+    #[test]
+    fn test_packet_set_checksum_twice() {
+        let mut packet = Packet::with_payload(b"Hello\n");
+        packet.set_checksum(0xdead_beef);
+        packet.set_checksum(0x0102_0304);
+        assert_eq!(packet.extensions().count(), 1);
+        assert_eq!(packet.checksum(), Some(0x0102_0304));
+        assert_eq!(packet.len(), HEADER_SIZE + CHECKSUM_EXTENSION_SIZE + 6);
+    }
+
     #[test]
     fn test_packet_encode() {
         let payload = b"Hello\n".to_vec();
@@ -726,6 +1418,137 @@ mod tests {
         assert!(packet.is_err());
     }
 
+    #[test]
+    fn test_decode_header() {
+        let buf = [0x21, 0x01, 0x41, 0xa7, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0x00, 0x00, 0x05, 0xdc, 0xab, 0x53, 0x3a, 0xf5,
+                   0x00, 0x04, 0x00, 0x00, 0x00, 0x00];
+        let header = PacketHeader::decode(&buf).unwrap();
+        assert_eq!(header.get_type(), State);
+        assert_eq!(header.get_version(), 1);
+        assert_eq!(header.get_extension_type(), ExtensionType::SelectiveAck);
+
+        // Too short
+        match PacketHeader::decode(&buf[..HEADER_SIZE - 1]) {
+            Err(ParseError::InvalidPacketLength) => (),
+            x => panic!("Expected Err(InvalidPacketLength), got {:?}", x.is_ok()),
+        }
+
+        // Unsupported version
+        let mut wrong_version = buf;
+        wrong_version[0] = 0x22;
+        match PacketHeader::decode(&wrong_version) {
+            Err(ParseError::UnsupportedVersion(2)) => (),
+            x => panic!("Expected Err(UnsupportedVersion(2)), got {:?}", x.is_ok()),
+        }
+
+        // Unknown packet type
+        let mut wrong_type = buf;
+        wrong_type[0] = 0x51;
+        match PacketHeader::decode(&wrong_type) {
+            Err(ParseError::InvalidPacketType(5)) => (),
+            x => panic!("Expected Err(InvalidPacketType(5)), got {:?}", x.is_ok()),
+        }
+
+        // Extension running past the end of the packet
+        match PacketHeader::decode(&buf[..buf.len() - 1]) {
+            Err(ParseError::TruncatedExtension) => (),
+            x => panic!("Expected Err(TruncatedExtension), got {:?}", x.is_ok()),
+        }
+        match PacketHeader::decode(&buf[..HEADER_SIZE]) {
+            Err(ParseError::TruncatedExtension) => (),
+            x => panic!("Expected Err(TruncatedExtension), got {:?}", x.is_ok()),
+        }
+
+        // Empty SACK bitmask
+        let mut wrong_length = buf;
+        wrong_length[HEADER_SIZE + 1] = 0;
+        match PacketHeader::decode(&wrong_length) {
+            Err(ParseError::InvalidExtensionLength) => (),
+            x => panic!("Expected Err(InvalidExtensionLength), got {:?}", x.is_ok()),
+        }
+    }
+
+    #[test]
+    fn test_header_byte_order() {
+        let mut packet = Packet::new();
+        packet.set_connection_id(0x0102);
+        packet.set_timestamp(0x03040506.into());
+        packet.set_timestamp_difference(0x0708090au32.into());
+        packet.set_wnd_size(0x0b0c0d0e);
+        packet.set_seq_nr(0x0f10);
+        packet.set_ack_nr(0x1112);
+        assert_eq!(packet.as_ref(),
+                   &[0x01, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+                     0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f, 0x10, 0x11, 0x12]);
+        assert_eq!(packet.header().to_bytes(), packet.as_ref());
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        for ty in 0..5 {
+            let mut packet = Packet::with_payload(&[1, 2, 3]);
+            packet.set_type(PacketType::try_from(ty).unwrap());
+            packet.set_connection_id(0xbeef);
+            packet.set_timestamp(0xdeadbeef.into());
+            packet.set_timestamp_difference(0x01234567u32.into());
+            packet.set_wnd_size(0x89abcdef);
+            packet.set_seq_nr(0xfffe);
+            packet.set_ack_nr(0x0001);
+            packet.set_sack(vec![0x80, 0, 0, 0]);
+
+            let header = packet.header();
+            assert_eq!(&header.to_bytes()[..], &packet.as_ref()[..HEADER_SIZE]);
+            assert_eq!(PacketHeader::decode(packet.as_ref()).unwrap(), header);
+
+            let decoded = Packet::try_from(packet.as_ref()).unwrap();
+            assert!(decoded == packet);
+            assert_eq!(decoded.get_type(), PacketType::try_from(ty).unwrap());
+            assert_eq!(decoded.connection_id(), 0xbeef);
+            assert_eq!(decoded.timestamp(), 0xdeadbeef.into());
+            assert_eq!(decoded.timestamp_difference(), 0x01234567u32.into());
+            assert_eq!(decoded.wnd_size(), 0x89abcdef);
+            assert_eq!(decoded.seq_nr(), 0xfffe);
+            assert_eq!(decoded.ack_nr(), 0x0001);
+            assert_eq!(decoded.payload(), &[1, 2, 3]);
+        }
+    }
+
+    // Any header built from random values is decoded back to the same values
+    #[test]
+    fn quicktest_header_round_trip() {
+        fn run(ty: u8, connection_id: u16, timestamp: u32, timestamp_difference: u32,
+               wnd_size: u32, seq_nr: u16, ack_nr: u16) -> TestResult {
+            let ty = match PacketType::try_from(ty % 5) {
+                Ok(ty) => ty,
+                Err(_) => return TestResult::failed(),
+            };
+            let mut packet = Packet::new();
+            packet.set_type(ty);
+            packet.set_connection_id(connection_id);
+            packet.set_timestamp(timestamp.into());
+            packet.set_timestamp_difference(timestamp_difference.into());
+            packet.set_wnd_size(wnd_size);
+            packet.set_seq_nr(seq_nr);
+            packet.set_ack_nr(ack_nr);
+
+            let header = packet.header();
+            let decoded = match PacketHeader::decode(&header.to_bytes()) {
+                Ok(decoded) => decoded,
+                Err(_) => return TestResult::failed(),
+            };
+            TestResult::from_bool(decoded == header &&
+                                  decoded.connection_id == connection_id &&
+                                  decoded.timestamp == timestamp &&
+                                  decoded.timestamp_difference == timestamp_difference &&
+                                  decoded.wnd_size == wnd_size &&
+                                  decoded.seq_nr == seq_nr &&
+                                  decoded.ack_nr == ack_nr)
+        }
+        QuickCheck::new().tests(1000)
+            .quickcheck(run as fn(u8, u16, u32, u32, u32, u16, u16) -> TestResult)
+    }
+
     #[test]
     fn test_decode_empty_packet() {
         let packet = Packet::try_from(&[]);
@@ -738,10 +1561,14 @@ mod tests {
         fn run(x: Vec<u8>) -> TestResult {
             let packet = Packet::try_from(&x);
 
-            if PacketHeader::try_from(&x).and(check_extensions(&x)).is_err() {
+            if PacketHeader::decode(&x).is_err() {
                 TestResult::from_bool(packet.is_err())
             } else if let Ok(packet) = packet {
-                TestResult::from_bool(&packet.as_ref() == &x.as_slice())
+                // Walking a decoded packet never goes out of bounds
+                let extensions_len = packet.extensions().fold(0, |acc, e| acc + e.len() + 2);
+                TestResult::from_bool(packet.as_ref() == x.as_slice() &&
+                                      HEADER_SIZE + extensions_len + packet.payload().len() ==
+                                      x.len())
             } else {
                 TestResult::from_bool(false)
             }
@@ -749,6 +1576,63 @@ mod tests {
         QuickCheck::new().tests(10000).quickcheck(run as fn(Vec<u8>) -> TestResult)
     }
 
+    #[test]
+    fn test_packet_debug() {
+        let mut packet = Packet::with_payload(&[0; 100]);
+        packet.set_seq_nr(5);
+        packet.set_ack_nr(4);
+        packet.set_sack(vec![1, 0, 0, 0]);
+        let formatted = format!("{:?}", packet);
+        assert!(formatted.contains("type: Data"));
+        assert!(formatted.contains("seq_nr: 5, ack_nr: 4"));
+        assert!(formatted.contains("extensions: [SelectiveAck (4 bytes)]"));
+        assert!(formatted.contains("payload: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]... \
+                                    (100 bytes)"));
+
+        let packet = Packet::new();
+        assert!(format!("{:?}", packet).contains("extensions: [], payload: [] (0 bytes)"));
+
+        let header = PacketHeader::decode(packet.as_ref()).unwrap();
+        assert!(format!("{:?}", header).starts_with("PacketHeader { type: Data, version: 1, "));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_packet_serde_round_trip() {
+
+        let mut packet = Packet::with_payload(b"abc");
+        packet.set_type(State);
+        packet.set_seq_nr(5);
+        packet.set_ack_nr(4);
+        packet.set_timestamp(Timestamp(1000));
+        packet.set_sack(vec![1, 0, 0, 0]);
+        packet.set_checksum(0xdeadbeef);
+
+        // Fields are stored decoded, not as wire bytes
+        let json = serde_json::to_value(&packet).unwrap();
+        assert_eq!(json["type"], "State");
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["seq_nr"], 5);
+        assert_eq!(json["timestamp"], 1000);
+        assert_eq!(json["extensions"][0]["kind"], 1);
+        assert_eq!(json["extensions"][0]["data"], serde_json::json!([1, 0, 0, 0]));
+        assert_eq!(json["payload"], serde_json::json!([97, 98, 99]));
+        assert_eq!(serde_json::from_value::<Packet>(json).unwrap(), packet);
+
+        let header = packet.header();
+        let json = serde_json::to_value(&header).unwrap();
+        assert_eq!(json["extension"], "SelectiveAck");
+        assert_eq!(serde_json::from_value::<PacketHeader>(json).unwrap(), header);
+
+        // Packets that couldn't be sent are rejected
+        let mut json = serde_json::to_value(&packet).unwrap();
+        json["version"] = 2.into();
+        assert!(serde_json::from_value::<Packet>(json).is_err());
+        let mut json = serde_json::to_value(&packet).unwrap();
+        json["extensions"][0]["data"] = serde_json::json!([1, 2, 3]);
+        assert!(serde_json::from_value::<Packet>(json).is_err());
+    }
+
     #[test]
     fn extension_iterator() {
         let buf = [0x21, 0x00, 0x41, 0xa8, 0x99, 0x2f, 0xd0, 0x2a, 0x9f, 0x4a,
@@ -762,7 +1646,7 @@ mod tests {
         let packet = Packet::try_from(&buf).unwrap();
         let extensions: Vec<Extension> = packet.extensions().collect();
         assert_eq!(extensions.len(), 1);
-        assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+        assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
         assert_eq!(extensions[0].data, &[0, 0, 0, 0]);
         assert_eq!(extensions[0].len(), extensions[0].data.len());
         assert_eq!(extensions[0].len(), 4);
@@ -775,11 +1659,11 @@ mod tests {
         let packet = Packet::try_from(&buf).unwrap();
         let extensions: Vec<Extension> = packet.extensions().collect();
         assert_eq!(extensions.len(), 2);
-        assert_eq!(extensions[0].ty, ExtensionType::SelectiveAck);
+        assert_eq!(extensions[0].get_type(), ExtensionType::SelectiveAck);
         assert_eq!(extensions[0].data, &[1, 2, 3, 4]);
         assert_eq!(extensions[0].len(), extensions[0].data.len());
         assert_eq!(extensions[0].len(), 4);
-        assert_eq!(extensions[1].ty, ExtensionType::Unknown(0xff));
+        assert_eq!(extensions[1].get_type(), ExtensionType::Unknown(0xff));
         assert_eq!(extensions[1].data, &[5, 6, 7, 8]);
         assert_eq!(extensions[1].len(), extensions[1].data.len());
         assert_eq!(extensions[1].len(), 4);
@@ -791,7 +1675,7 @@ mod bench {
     extern crate test;
 
     use self::test::Bencher;
-    use packet::{Packet, TryFrom};
+    use crate::packet::{Packet, TryFrom};
 
     #[bench]
     fn bench_decode(b: &mut Bencher) {
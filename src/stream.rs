@@ -1,12 +1,18 @@
-use std::io::{Read, Write, Result};
-use std::net::{ToSocketAddrs, SocketAddr};
-use socket::UtpSocket;
+use std::io::{self, IoSlice, Read, Write};
+#[cfg(feature = "unstable")]
+use std::io::BorrowedCursor;
+use std::net::{ToSocketAddrs, Shutdown, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use crate::socket::{self, UtpSocket, Backoff, Canceller, CloseReason, ConnectionStats, SendLimit};
+use crate::socket::SocketState;
+use crate::error::Result;
 
 /// A structure that represents a uTP (Micro Transport Protocol) stream between a local socket and a
 /// remote socket.
 ///
-/// The connection will be closed when the value is dropped (either explicitly or when it goes out
-/// of scope).
+/// The connection will be closed when the value and all its clones made with `try_clone` are
+/// dropped (either explicitly or when they go out of scope).
 ///
 /// The default maximum retransmission retries is 5, which translates to about 16 seconds. It can be
 /// changed by calling `set_max_retransmission_retries`. Notice that the initial congestion timeout
@@ -22,8 +28,22 @@ use socket::UtpSocket;
 /// let _ = stream.write(&[1]);
 /// let _ = stream.read(&mut [0; 1000]);
 /// ```
+///
+/// Each read returns as much of the data received in order as fits in the buffer, across
+/// packets, and keeps what doesn't fit for the next one. Wrapping the stream in a `BufReader`
+/// therefore serves small reads, such as reading line by line, without waiting for packets:
+///
+/// ```no_run
+/// use utp::UtpStream;
+/// use std::io::{BufRead, BufReader};
+///
+/// let stream = UtpStream::connect("127.0.0.1:1234").expect("Error connecting");
+/// for line in BufReader::new(stream).lines() {
+///     println!("{}", line.expect("Error reading"));
+/// }
+/// ```
 pub struct UtpStream {
-    socket: UtpSocket,
+    socket: Arc<Mutex<UtpSocket>>,
 }
 
 impl UtpStream {
@@ -32,9 +52,10 @@ impl UtpStream {
     /// The address type can be any implementer of the `ToSocketAddr` trait. See its documentation
     /// for concrete examples.
     ///
-    /// If more than one valid address is specified, only the first will be used.
+    /// If more than one valid address is specified, the stream is bound to the first one that
+    /// succeeds.
     pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UtpStream> {
-        UtpSocket::bind(addr).map(|s| UtpStream { socket: s })
+        UtpSocket::bind(addr).map(UtpStream::from)
     }
 
     /// Opens a uTP connection to a remote host by hostname or IP address.
@@ -42,55 +63,544 @@ impl UtpStream {
     /// The address type can be any implementer of the `ToSocketAddr` trait. See its documentation
     /// for concrete examples.
     ///
-    /// If more than one valid address is specified, only the first will be used.
+    /// If more than one valid address is specified, each is tried in turn until a connection is
+    /// established.
     pub fn connect<A: ToSocketAddrs>(dst: A) -> Result<UtpStream> {
         // Port 0 means the operating system gets to choose it
-        UtpSocket::connect(dst).map(|s| UtpStream { socket: s })
+        UtpSocket::connect(dst).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host, giving up if it isn't established before `timeout`
+    /// elapses.
+    ///
+    /// See `UtpSocket::connect_timeout` for details.
+    pub fn connect_timeout<A: ToSocketAddrs>(dst: A, timeout: Duration) -> Result<UtpStream> {
+        UtpSocket::connect_timeout(dst, timeout).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host, making up to `attempts` connection requests with
+    /// jittered exponential backoff from `base_delay`.
+    ///
+    /// See `UtpSocket::connect_retry` for details.
+    pub fn connect_retry<A: ToSocketAddrs>(dst: A, attempts: u32, base_delay: Duration)
+                                           -> Result<UtpStream> {
+        UtpSocket::connect_retry(dst, attempts, base_delay).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host, sending `data` along with the connection request.
+    ///
+    /// See `UtpSocket::connect_with_data` for details, including ordering guarantees.
+    pub fn connect_with_data<A: ToSocketAddrs>(dst: A, data: &[u8]) -> Result<UtpStream> {
+        UtpSocket::connect_with_data(dst, data).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host with the given connection identifier.
+    ///
+    /// See `UtpSocket::connect_with_id` for details.
+    pub fn connect_with_id<A: ToSocketAddrs>(dst: A, id: u16) -> Result<UtpStream> {
+        UtpSocket::connect_with_id(dst, id).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host from the given local address.
+    ///
+    /// See `UtpSocket::connect_from` for details, including simultaneous connections.
+    pub fn connect_from<A: ToSocketAddrs, B: ToSocketAddrs>(local: A, dst: B)
+                                                           -> Result<UtpStream> {
+        UtpSocket::connect_from(local, dst).map(UtpStream::from)
+    }
+
+    /// Opens a uTP connection to a remote host over an already bound UDP socket.
+    ///
+    /// See `UtpSocket::from_udp` for details, including what the UDP socket may no longer be used
+    /// for.
+    pub fn from_udp(socket: UdpSocket, peer: SocketAddr) -> Result<UtpStream> {
+        UtpSocket::from_udp(socket, peer).map(UtpStream::from)
+    }
+
+    /// Creates a new handle to the same uTP stream, for example to read from it in one thread
+    /// while writing to it in another.
+    ///
+    /// Both handles share the connection state. A thread blocked reading doesn't prevent others
+    /// from writing, but concurrent writes from several threads may be interleaved in any order.
+    /// Closing either handle closes the connection for both.
+    pub fn try_clone(&self) -> Result<UtpStream> {
+        Ok(UtpStream { socket: self.socket.clone() })
     }
 
     /// Gracefully closes connection to peer.
     ///
     /// This method allows both peers to receive all packets still in
-    /// flight.
+    /// flight. See `UtpSocket::close` for details.
     pub fn close(&mut self) -> Result<()> {
-        self.socket.close()
+        self.lock().close()
+    }
+
+    /// Shuts down the reading half, the writing half or both halves of the connection.
+    ///
+    /// After shutting down the writing half, the remote peer reads the end of the stream but may
+    /// keep writing until it closes the connection. See `UtpSocket::shutdown` for details.
+    pub fn shutdown(&self, how: Shutdown) -> Result<()> {
+        self.lock().shutdown(how)
+    }
+
+    /// Splits this stream into a reading half and a writing half, which can be moved to separate
+    /// threads.
+    ///
+    /// Closing the writing half sends a FIN to the remote peer, after which the reading half still
+    /// returns the data received so far before reporting the end of the stream. The connection is
+    /// closed when both halves are dropped.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let read = OwnedReadHalf { socket: self.socket.clone() };
+        (read, OwnedWriteHalf { socket: self.socket })
     }
 
     /// Returns the socket address of the local half of this uTP connection.
     pub fn local_addr(&self) -> Result<SocketAddr> {
-        self.socket.local_addr()
+        self.lock().local_addr()
+    }
+
+    /// Returns the socket address of the remote half of this uTP connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        self.lock().peer_addr()
+    }
+
+    /// Sets the time-to-live of the packets sent by the underlying socket.
+    ///
+    /// See `UtpSocket::set_ttl` for details.
+    pub fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.lock().set_ttl(ttl)
+    }
+
+    /// Returns the time-to-live of the packets sent by the underlying socket.
+    pub fn ttl(&self) -> Result<u32> {
+        self.lock().ttl()
+    }
+
+    /// Sets the type of service byte of the packets sent by the underlying socket.
+    ///
+    /// See `UtpSocket::set_tos` for details.
+    pub fn set_tos(&self, tos: u8) -> Result<()> {
+        self.lock().set_tos(tos)
+    }
+
+    /// Returns the type of service byte of the packets sent by the underlying socket.
+    pub fn tos(&self) -> u8 {
+        self.lock().tos()
+    }
+
+    /// Returns the state of the underlying connection.
+    pub fn state(&self) -> SocketState {
+        self.lock().state()
+    }
+
+    /// Returns why the underlying connection ended, if it did.
+    ///
+    /// See `UtpSocket::close_reason` for details.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.lock().close_reason()
+    }
+
+    /// Returns statistics about the underlying connection.
+    pub fn stats(&self) -> ConnectionStats {
+        self.lock().stats()
+    }
+
+    /// Reads data without ever blocking, returning `None` if none is ready.
+    ///
+    /// See `UtpSocket::try_recv` for details.
+    pub fn try_read(&mut self, buf: &mut [u8]) -> Result<Option<usize>> {
+        self.lock().try_recv(buf)
+    }
+
+    /// Returns whether the remote peer finished sending and all of its data was read.
+    ///
+    /// See `UtpSocket::peer_finished` for details.
+    pub fn peer_finished(&self) -> bool {
+        self.lock().peer_finished()
+    }
+
+    /// Returns the latest receive window the remote peer advertised, in bytes.
+    ///
+    /// See `UtpSocket::peer_window` for details.
+    pub fn peer_window(&self) -> u32 {
+        self.lock().peer_window()
+    }
+
+    /// Returns what keeps the underlying socket from sending right away, if anything.
+    ///
+    /// See `UtpSocket::send_limit_reason` for details.
+    pub fn send_limit_reason(&self) -> Option<SendLimit> {
+        self.lock().send_limit_reason()
+    }
+
+    /// Returns the smallest one-way delay of the packets sent by the underlying socket recently.
+    ///
+    /// See `UtpSocket::base_delay` for details.
+    pub fn base_delay(&self) -> Duration {
+        self.lock().base_delay()
+    }
+
+    /// Returns the current one-way delay estimate of the packets sent by the underlying socket.
+    ///
+    /// See `UtpSocket::current_delay` for details.
+    pub fn current_delay(&self) -> Duration {
+        self.lock().current_delay()
+    }
+
+    /// Sets the read timeout of the underlying socket.
+    ///
+    /// See `UtpSocket::set_read_timeout` for details.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.lock().set_read_timeout(timeout)
+    }
+
+    /// Returns the read timeout of the underlying socket.
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.lock().read_timeout()
+    }
+
+    /// Sets the write timeout of the underlying socket.
+    ///
+    /// See `UtpSocket::set_write_timeout` for details.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.lock().set_write_timeout(timeout)
+    }
+
+    /// Returns the write timeout of the underlying socket.
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.lock().write_timeout()
+    }
+
+    /// Sets how long closing the underlying socket may block to deliver buffered data, or makes it
+    /// discard the data right away if `None` is passed.
+    ///
+    /// See `UtpSocket::set_linger` for details.
+    pub fn set_linger(&mut self, linger: Option<Duration>) -> Result<()> {
+        self.lock().set_linger(linger)
+    }
+
+    /// Returns how long closing the underlying socket may block, if at all.
+    pub fn linger(&self) -> Option<Duration> {
+        self.lock().linger()
+    }
+
+    /// Sets the interval between keep-alive packets on the underlying socket, or disables them if
+    /// `None` is passed.
+    ///
+    /// See `UtpSocket::set_keepalive` for details.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) -> Result<()> {
+        self.lock().set_keepalive(interval)
+    }
+
+    /// Returns the interval between keep-alive packets on the underlying socket, if enabled.
+    pub fn keepalive(&self) -> Option<Duration> {
+        self.lock().keepalive()
+    }
+
+    /// Sets how long the underlying socket may go without receiving anything before closing the
+    /// connection, or disables the idle timeout if `None` is passed.
+    ///
+    /// See `UtpSocket::set_idle_timeout` for details.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.lock().set_idle_timeout(timeout)
+    }
+
+    /// Returns how long the underlying socket may go without receiving anything, if limited.
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.lock().idle_timeout()
+    }
+
+    /// Sets how long the connection may last at most before the underlying socket resets it, or
+    /// lets it last indefinitely if `None` is passed.
+    ///
+    /// See `UtpSocket::set_max_lifetime` for details.
+    pub fn set_max_lifetime(&mut self, lifetime: Option<Duration>) -> Result<()> {
+        self.lock().set_max_lifetime(lifetime)
+    }
+
+    /// Returns how long the connection may last at most, if limited.
+    pub fn max_lifetime(&self) -> Option<Duration> {
+        self.lock().max_lifetime()
+    }
+
+    /// Sets how long the underlying socket may delay acknowledgements of in-order data, or
+    /// disables delayed acknowledgements if `None` is passed.
+    ///
+    /// See `UtpSocket::set_ack_delay` for details.
+    pub fn set_ack_delay(&mut self, delay: Option<Duration>) -> Result<()> {
+        self.lock().set_ack_delay(delay)
+    }
+
+    /// Returns how long the underlying socket may delay acknowledgements, if enabled.
+    pub fn ack_delay(&self) -> Option<Duration> {
+        self.lock().ack_delay()
+    }
+
+    /// Moves the underlying socket into or out of non-blocking mode.
+    ///
+    /// In non-blocking mode, `read`, `write`, `flush` and `close` return an error of kind
+    /// `ErrorKind::WouldBlock` instead of waiting. See `UtpSocket::set_nonblocking` for details.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        self.lock().set_nonblocking(nonblocking)
+    }
+
+    /// Returns a handle cancelling the blocking reads, writes and flushes of this stream and its
+    /// clones from another thread, see `UtpSocket::canceller`.
+    pub fn canceller(&self) -> Canceller {
+        self.lock().canceller()
+    }
+
+    /// Returns whether reading would return right away rather than block or fail with
+    /// `ErrorKind::WouldBlock`.
+    ///
+    /// See `UtpSocket::is_read_ready` for details.
+    pub fn is_read_ready(&self) -> bool {
+        self.lock().is_read_ready()
+    }
+
+    /// Returns whether writing would make progress right away rather than block or fail with
+    /// `ErrorKind::WouldBlock`.
+    ///
+    /// See `UtpSocket::is_write_ready` for details.
+    pub fn is_write_ready(&self) -> bool {
+        self.lock().is_write_ready()
+    }
+
+    /// Sets the maximum payload of the data packets sent by the underlying socket, in bytes.
+    ///
+    /// See `UtpSocket::set_max_payload_size` for details.
+    pub fn set_max_payload_size(&mut self, size: usize) -> Result<()> {
+        self.lock().set_max_payload_size(size)
+    }
+
+    /// Returns the maximum payload of the data packets sent by the underlying socket, in bytes.
+    pub fn max_payload_size(&self) -> usize {
+        self.lock().max_payload_size()
+    }
+
+    /// Enables or disables path MTU discovery on the underlying socket.
+    ///
+    /// See `UtpSocket::set_path_mtu_discovery` for details.
+    pub fn set_path_mtu_discovery(&mut self, enabled: bool) {
+        self.lock().set_path_mtu_discovery(enabled);
+    }
+
+    /// Returns whether the underlying socket does path MTU discovery.
+    pub fn path_mtu_discovery(&self) -> bool {
+        self.lock().path_mtu_discovery()
+    }
+
+    /// Returns how many bytes of data a single packet of the underlying socket carries at most.
+    ///
+    /// See `UtpSocket::max_segment_size` for details.
+    pub fn max_segment_size(&self) -> usize {
+        self.lock().max_segment_size()
+    }
+
+    /// Sets how many written bytes the underlying socket may hold until acknowledged.
+    ///
+    /// See `UtpSocket::set_send_buffer_size` for details.
+    pub fn set_send_buffer_size(&mut self, size: usize) -> Result<()> {
+        self.lock().set_send_buffer_size(size)
+    }
+
+    /// Returns how many written bytes the underlying socket may hold until acknowledged.
+    pub fn send_buffer_size(&self) -> usize {
+        self.lock().send_buffer_size()
+    }
+
+    /// Sets how many received bytes the underlying socket buffers until they are read.
+    ///
+    /// See `UtpSocket::set_recv_buffer_size` for details.
+    pub fn set_recv_buffer_size(&mut self, size: usize) -> Result<()> {
+        self.lock().set_recv_buffer_size(size)
+    }
+
+    /// Returns how many received bytes the underlying socket buffers until they are read.
+    pub fn recv_buffer_size(&self) -> usize {
+        self.lock().recv_buffer_size()
+    }
+
+    /// Enables or disables sending small writes right away on the underlying socket.
+    ///
+    /// See `UtpSocket::set_nodelay` for details.
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.lock().set_nodelay(nodelay);
+    }
+
+    /// Returns whether the underlying socket sends small writes right away.
+    pub fn nodelay(&self) -> bool {
+        self.lock().nodelay()
+    }
+
+    /// Limits the average rate at which the underlying socket sends, in bytes per second, or
+    /// removes the limit if `None` is passed.
+    ///
+    /// See `UtpSocket::set_max_send_rate` for details.
+    pub fn set_max_send_rate(&mut self, rate: Option<u64>) -> Result<()> {
+        self.lock().set_max_send_rate(rate)
+    }
+
+    /// Returns the maximum average rate at which the underlying socket sends, if limited.
+    pub fn max_send_rate(&self) -> Option<u64> {
+        self.lock().max_send_rate()
+    }
+
+    /// Sets the queuing delay the congestion controller of the underlying socket aims for.
+    ///
+    /// See `UtpSocket::set_target_delay` for details.
+    pub fn set_target_delay(&mut self, target: Duration) -> Result<()> {
+        self.lock().set_target_delay(target)
+    }
+
+    /// Sets the congestion window the underlying socket starts sending with, in bytes.
+    ///
+    /// See `UtpSocket::set_initial_window` for details.
+    pub fn set_initial_window(&mut self, bytes: u32) -> Result<()> {
+        self.lock().set_initial_window(bytes)
+    }
+
+    /// Sets how quickly the congestion controller of the underlying socket reacts to delay
+    /// variations.
+    ///
+    /// See `UtpSocket::set_gain` for details.
+    pub fn set_gain(&mut self, gain: f64) -> Result<()> {
+        self.lock().set_gain(gain)
     }
 
     /// Changes the maximum number of retransmission retries on the underlying socket.
     pub fn set_max_retransmission_retries(&mut self, n: u32) {
-        self.socket.max_retransmission_retries = n;
+        self.lock().set_max_retransmissions(n);
+    }
+
+    /// Sets how the retransmission timeout of the underlying socket grows on consecutive timeouts.
+    ///
+    /// See `UtpSocket::set_backoff` for details.
+    pub fn set_backoff(&mut self, backoff: Backoff) -> Result<()> {
+        self.lock().set_backoff(backoff)
+    }
+
+    /// Locks the underlying socket, shared with the clones of this stream.
+    fn lock(&self) -> MutexGuard<'_, UtpSocket> {
+        socket::lock(&self.socket)
     }
 }
 
 impl Read for UtpStream {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.socket.recv_from(buf).map(|(read, _src)| read)
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Only wait without holding the lock when some clone might need it in the meantime
+        if Arc::strong_count(&self.socket) == 1 {
+            Ok(self.lock().recv(buf)?)
+        } else {
+            Ok(socket::recv_from_shared(&self.socket, buf).map(|(read, _src)| read)?)
+        }
+    }
+
+    /// Reads data into a buffer that may not be initialized yet, without zeroing it first.
+    #[cfg(feature = "unstable")]
+    fn read_buf(&mut self, mut cursor: BorrowedCursor) -> io::Result<()> {
+        // The receive path only writes the bytes it reads, which `advance` then marks as filled
+        let buf = unsafe { cursor.as_mut() };
+        let (read, _src) = if Arc::strong_count(&self.socket) == 1 {
+            self.lock().recv_from_uninit(buf)?
+        } else {
+            socket::recv_from_shared_uninit(&self.socket, buf)?
+        };
+        unsafe { cursor.advance(read) };
+        Ok(())
     }
 }
 
 impl Write for UtpStream {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.socket.send_to(buf)
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.lock().send(buf)?)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        Ok(self.lock().send_vectored(bufs)?)
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.socket.flush()
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.lock().flush()?)
     }
 }
 
 impl From<UtpSocket> for UtpStream {
     fn from(socket: UtpSocket) -> Self {
-        UtpStream { socket: socket }
+        UtpStream { socket: Arc::new(Mutex::new(socket)) }
     }
 }
 
 impl AsMut<UtpSocket> for UtpStream {
+    /// Returns the underlying socket.
+    ///
+    /// # Panics
+    ///
+    /// Panics if some clone of this stream made with `try_clone` is still alive.
     fn as_mut(&mut self) -> &mut UtpSocket {
-        &mut self.socket
+        let socket = Arc::get_mut(&mut self.socket).expect("The stream has live clones");
+        socket.get_mut().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The reading half of a `UtpStream`, created by `UtpStream::into_split`.
+pub struct OwnedReadHalf {
+    socket: Arc<Mutex<UtpSocket>>,
+}
+
+impl OwnedReadHalf {
+    /// Returns the socket address of the local half of this uTP connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        socket::lock(&self.socket).local_addr()
+    }
+
+    /// Returns the socket address of the remote half of this uTP connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        socket::lock(&self.socket).peer_addr()
+    }
+}
+
+impl Read for OwnedReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(socket::recv_from_shared(&self.socket, buf).map(|(read, _src)| read)?)
+    }
+}
+
+/// The writing half of a `UtpStream`, created by `UtpStream::into_split`.
+pub struct OwnedWriteHalf {
+    socket: Arc<Mutex<UtpSocket>>,
+}
+
+impl OwnedWriteHalf {
+    /// Gracefully closes connection to peer, once all data written so far is acknowledged.
+    ///
+    /// Data received while closing remains readable from the reading half. See
+    /// `UtpSocket::close` for details.
+    pub fn close(&mut self) -> Result<()> {
+        socket::lock(&self.socket).close()
+    }
+
+    /// Returns the socket address of the local half of this uTP connection.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        socket::lock(&self.socket).local_addr()
+    }
+
+    /// Returns the socket address of the remote half of this uTP connection.
+    pub fn peer_addr(&self) -> Result<SocketAddr> {
+        socket::lock(&self.socket).peer_addr()
+    }
+}
+
+impl Write for OwnedWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(socket::lock(&self.socket).send_to(buf)?)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        Ok(socket::lock(&self.socket).send_vectored(bufs)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(socket::lock(&self.socket).flush()?)
     }
 }
@@ -1,30 +1,82 @@
-use std::time;
-use std::fmt;
-use std::ops::Sub;
-use num_traits::ToPrimitive;
+use core::fmt;
+use core::ops::Sub;
+#[cfg(feature = "std")] use std::time;
+#[cfg(feature = "std")] use num_traits::ToPrimitive;
 
 /// Return current time in microseconds since the UNIX epoch.
+///
+/// Like the timestamps of uTP packets, the result only keeps the lowest 32 bits of the actual
+/// value, wrapping around about every 71 minutes.
+#[cfg(feature = "std")]
 pub fn now_microseconds() -> Timestamp {
     let t = time::SystemTime::now()
         .duration_since(time::UNIX_EPOCH)
         .unwrap_or_else(|e| e.duration());
-    (t.as_secs().wrapping_mul(1_000_000) as u32).wrapping_add(t.subsec_nanos() / 1000).into()
+    microseconds(t)
 }
 
-#[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
+/// Converts a duration to microseconds, modulo 2^32.
+#[cfg(feature = "std")]
+fn microseconds(t: time::Duration) -> Timestamp {
+    Timestamp(t.as_micros() as u32)
+}
+
+/// A source of the microsecond timestamps used to stamp packets and measure delays.
+///
+/// Sockets use `SystemClock`, while tests may inject a clock they control so that delay and
+/// round-trip measurements don't depend on how long things take.
+#[cfg(feature = "std")]
+pub trait Clock: Send + Sync {
+    /// Returns the current time in microseconds, wrapping around like `now_microseconds`.
+    fn now_microseconds(&self) -> Timestamp;
+}
+
+/// The real clock, as read by `now_microseconds`.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now_microseconds(&self) -> Timestamp {
+        now_microseconds()
+    }
+}
+
+/// A clock that only moves forward when told to.
+#[cfg(test)]
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(::std::sync::Arc<::std::sync::atomic::AtomicU32>);
+
+#[cfg(test)]
+impl ManualClock {
+    /// Moves the clock (and all of its clones) forward.
+    pub fn advance(&self, by: time::Duration) {
+        use std::sync::atomic::Ordering;
+        self.0.fetch_add(by.as_micros() as u32, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now_microseconds(&self) -> Timestamp {
+        Timestamp(self.0.load(::std::sync::atomic::Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq, Default)]
 pub struct Timestamp(pub u32);
 
+/// Timestamps wrap around, so their difference is taken modulo 2^32, as a signed 32-bit value: a
+/// timestamp taken shortly after wrapping around is later than one taken shortly before.
+///
+/// Truncated to 32 bits, the difference is also what the `timestamp_difference` header field
+/// holds, even when it's taken between the clocks of two different hosts.
 impl Sub for Timestamp {
     type Output = Delay;
 
     fn sub(self, other: Timestamp) -> Delay {
-        Delay(self.0 as i64 - other.0 as i64)
-    }
-}
-
-impl Default for Timestamp {
-    fn default() -> Timestamp {
-        Timestamp(0)
+        Delay(self.0.wrapping_sub(other.0) as i32 as i64)
     }
 }
 
@@ -40,7 +92,7 @@ impl From<Timestamp> for u32 {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq)]
+#[derive(Debug, Copy, Clone, PartialOrd, PartialEq, Ord, Eq, Default)]
 pub struct Delay(pub i64);
 
 impl From<i64> for Delay {
@@ -61,12 +113,6 @@ impl From<Delay> for u32 {
     }
 }
 
-impl Default for Delay {
-    fn default() -> Delay {
-        Delay(0)
-    }
-}
-
 impl Sub for Delay {
     type Output = Delay;
 
@@ -81,6 +127,7 @@ impl fmt::Display for Delay {
     }
 }
 
+#[cfg(feature = "std")]
 impl ToPrimitive for Delay {
     fn to_i64(&self) -> Option<i64> {
         Some(self.0)
@@ -90,3 +137,34 @@ impl ToPrimitive for Delay {
         Some(self.0 as u64)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use crate::time::{microseconds, Delay, Timestamp};
+
+    #[test]
+    fn test_microseconds_wrap_around() {
+        const WRAP: u64 = 1 << 32;
+        assert_eq!(microseconds(Duration::from_micros(WRAP - 1)), Timestamp(u32::MAX));
+        assert_eq!(microseconds(Duration::from_micros(WRAP)), Timestamp(0));
+        assert_eq!(microseconds(Duration::from_micros(3 * WRAP + 42)), Timestamp(42));
+
+        // Seconds and microseconds are combined before truncating
+        let t = Duration::new(4295, 123_456_000);
+        assert_eq!(microseconds(t), Timestamp((4_295_123_456u64 % WRAP) as u32));
+    }
+
+    #[test]
+    fn test_timestamp_difference_wrap_around() {
+        let before = Timestamp(u32::MAX - 4);
+        let after = Timestamp(5);
+        assert_eq!(after - before, Delay(10));
+        assert_eq!(before - after, Delay(-10));
+        assert_eq!(Timestamp(2000) - Timestamp(500), Delay(1500));
+
+        // The header field holds the difference modulo 2^32
+        assert_eq!(u32::from(before - after), 10u32.wrapping_neg());
+        assert_eq!(Timestamp(5) - Timestamp(10u32.wrapping_neg()), Delay(15));
+    }
+}
@@ -0,0 +1,928 @@
+#[cfg(test)] use std::cell::Cell;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::packet::{Packet, PacketType, TryFrom, HEADER_SIZE};
+use crate::socket::BUF_SIZE;
+use crate::util::RateLimiter;
+
+// Datagrams waiting to be read by a single connection, or by `accept`. Further datagrams are
+// dropped, as if lost on the network.
+const MAX_QUEUED_DATAGRAMS: usize = 1024;
+
+// Resets sent in reply to packets of unknown connections, at most, so that spoofed packets can't
+// turn the socket into an amplifier.
+const MAX_RESETS_PER_SECOND: u32 = 32;
+
+// Attempts at sending a datagram while the system is short of buffer space, and the wait before
+// the first retry, doubling after each one.
+const MAX_SEND_ATTEMPTS: u32 = 4;
+const SEND_BACKOFF: Duration = Duration::from_millis(1);
+
+#[cfg(test)]
+thread_local! {
+    /// Makes sends from the current thread fail with an error of the given kind, such as
+    /// `WouldBlock` as if the UDP send buffer was full.
+    pub static SEND_ERROR: Cell<Option<ErrorKind>> = const { Cell::new(None) };
+}
+
+/// A datagram transport to carry uTP packets over instead of UDP, such as an encrypted tunnel, a
+/// relay or an in-memory link in tests. See `UtpSocket::bind_transport`.
+///
+/// It's used like a `UdpSocket` by a single connection: `recv_from` waits for the next datagram,
+/// failing with an error of kind `WouldBlock` or `TimedOut` if none arrives before the read
+/// timeout, or right away in non-blocking mode. The retransmission timers rely on it.
+pub trait DatagramTransport: Send + Sync {
+    /// Sends a datagram to `addr`, returning the number of bytes sent.
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize>;
+
+    /// Receives a datagram, returning its length and the address it came from. A datagram longer
+    /// than `buf` is truncated.
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Sets how long `recv_from` waits for a datagram, forever if `None`.
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Makes `recv_from` fail with an error of kind `WouldBlock` rather than wait, if set.
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Receives a datagram like `recv_from` on a blocking transport, but fails with an error of
+    /// kind `WouldBlock` rather than wait if none is available.
+    ///
+    /// This switches the transport to non-blocking mode for the call by default. Transports able
+    /// to receive without waiting otherwise should do so instead.
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.set_nonblocking(true)?;
+        let result = self.recv_from(buf);
+        self.set_nonblocking(false)?;
+        result
+    }
+
+    /// Returns the local address of this transport, if it has one.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::Error::new(ErrorKind::Unsupported, "the transport has no local address"))
+    }
+
+    /// Waits up to `timeout` for a datagram to be available, without consuming it.
+    ///
+    /// This merely sleeps by default, for a millisecond at most, so that waiting turns into
+    /// polling. Transports able to peek at their next datagram should do so instead.
+    fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        thread::sleep(::std::cmp::min(timeout, Duration::from_millis(1)));
+        Ok(())
+    }
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UdpSocket::set_nonblocking(self, nonblocking)
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        recv_nonblocking(self, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        let _ = self.peek_from(&mut [0; 1]);
+        Ok(())
+    }
+}
+
+/// The UDP socket a connection sends and receives its packets through.
+///
+/// A connection either has a UDP socket to itself, or shares one with the other connections of
+/// a `UtpContext`, or goes through another `DatagramTransport`. Either way, it's used like a
+/// `UdpSocket`.
+#[derive(Clone)]
+pub enum Transport {
+    /// A UDP socket used by a single connection.
+    Owned(Arc<UdpSocket>),
+    /// A connection's share of a UDP socket, receiving only the packets of that connection.
+    Shared(Arc<Endpoint>),
+    /// Another datagram transport, used by a single connection.
+    Custom(Arc<dyn DatagramTransport>),
+}
+
+/// Returns whether sending a datagram failed for lack of buffer space, on the host or in the
+/// socket's send buffer, which is likely to be freed shortly.
+fn is_out_of_buffers(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        if e.raw_os_error() == Some(::libc::ENOBUFS) {
+            return true;
+        }
+    }
+    e.kind() == ErrorKind::WouldBlock
+}
+
+/// Receives a datagram from `socket` without waiting, whether it's in non-blocking mode or not,
+/// leaving the mode alone for the other users of the socket.
+#[cfg(unix)]
+fn recv_nonblocking(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    use std::mem;
+    use std::net::{SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut addr_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let read = unsafe {
+        libc::recvfrom(socket.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(),
+                       libc::MSG_DONTWAIT,
+                       &mut addr as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+                       &mut addr_len)
+    };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let src = match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(&addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port),
+                                             addr.sin6_flowinfo, addr.sin6_scope_id))
+        }
+        _ => return Err(io::Error::new(ErrorKind::InvalidData, "unexpected address family")),
+    };
+    Ok((read as usize, src))
+}
+
+/// Receives a datagram from `socket` without waiting, switching it to non-blocking mode for the
+/// call where the system has no flag for it.
+#[cfg(not(unix))]
+fn recv_nonblocking(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    socket.set_nonblocking(true)?;
+    let result = socket.recv_from(buf);
+    socket.set_nonblocking(false)?;
+    result
+}
+
+/// Returns the error of the operations only UDP sockets support.
+fn unsupported() -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, "the transport isn't a UDP socket")
+}
+
+impl Transport {
+    /// Returns the underlying UDP socket, shared with other connections or not, unless the
+    /// transport isn't one.
+    pub fn udp(&self) -> Option<&UdpSocket> {
+        match *self {
+            Transport::Owned(ref socket) => Some(socket),
+            Transport::Shared(ref endpoint) => Some(&endpoint.shared.socket),
+            Transport::Custom(_) => None,
+        }
+    }
+
+    /// Sends a datagram to `addr`, recovering from transient errors: interrupted sends are
+    /// retried right away, and those failing for lack of buffer space a few times, waiting a
+    /// little longer each time.
+    ///
+    /// Buffers still full after that end up as an error of kind `WouldBlock`, which leaves the
+    /// datagram to be sent again later, like a lost one. Any other error is returned as is.
+    pub fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let (mut attempts, mut backoff) = (1, SEND_BACKOFF);
+        loop {
+            match self.try_send_to(buf, &addr) {
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if is_out_of_buffers(e) && attempts < MAX_SEND_ATTEMPTS => {
+                    thread::sleep(backoff);
+                    attempts += 1;
+                    backoff *= 2;
+                }
+                Err(ref e) if is_out_of_buffers(e) => return Err(ErrorKind::WouldBlock.into()),
+                result => return result,
+            }
+        }
+    }
+
+    fn try_send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        #[cfg(test)]
+        {
+            if let Some(kind) = SEND_ERROR.with(Cell::get) {
+                return Err(kind.into());
+            }
+        }
+        match *self {
+            Transport::Custom(ref transport) => match addr.to_socket_addrs()?.next() {
+                Some(addr) => transport.send_to(buf, addr),
+                None => Err(io::Error::new(ErrorKind::InvalidInput, "no address to send to")),
+            },
+            _ => self.udp().ok_or_else(unsupported)?.send_to(buf, addr),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Transport::Custom(ref transport) => transport.local_addr(),
+            _ => self.udp().ok_or_else(unsupported)?.local_addr(),
+        }
+    }
+
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        self.udp().ok_or_else(unsupported)?.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.udp().ok_or_else(unsupported)?.ttl()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match *self {
+            Transport::Owned(ref socket) => socket.set_nonblocking(nonblocking),
+            Transport::Custom(ref transport) => transport.set_nonblocking(nonblocking),
+            Transport::Shared(ref endpoint) => {
+                endpoint.nonblocking.store(nonblocking, Ordering::Relaxed);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Transport::Owned(ref socket) => socket.set_read_timeout(timeout),
+            Transport::Custom(ref transport) => transport.set_read_timeout(timeout),
+            Transport::Shared(ref endpoint) => {
+                if timeout == Some(Duration::from_secs(0)) {
+                    return Err(io::Error::new(ErrorKind::InvalidInput,
+                                              "cannot set a 0 duration timeout"));
+                }
+                *endpoint.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+                Ok(())
+            }
+        }
+    }
+
+    /// Lets the other connections sharing the UDP socket know when this one next needs its
+    /// timers handled. Does nothing for a UDP socket used by a single connection.
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        if let Transport::Shared(ref endpoint) = *self {
+            endpoint.set_deadline(deadline);
+        }
+    }
+
+    /// Returns the token of the connection if it shares the UDP socket of `shared`.
+    pub fn token_in(&self, shared: &Arc<Shared>) -> Option<u64> {
+        match *self {
+            Transport::Shared(ref endpoint) if Arc::ptr_eq(&endpoint.shared, shared) => {
+                Some(endpoint.token)
+            }
+            _ => None,
+        }
+    }
+
+    /// Reserves room for the connection to buffer between `min` and `max` bytes, as much as the
+    /// buffer budget of a shared UDP socket allows, replacing its previous reservation.
+    ///
+    /// Returns the bytes reserved, or `None` if fewer than `min` are available, in which case the
+    /// previous reservation stands. Without a budget, this is always `max`.
+    pub fn reserve_buffers(&self, min: usize, max: usize) -> Option<usize> {
+        match *self {
+            Transport::Owned(_) | Transport::Custom(_) => Some(max),
+            Transport::Shared(ref endpoint) => endpoint.reserve_buffers(min, max),
+        }
+    }
+
+    /// Lets the other connections sharing the UDP socket know how many bytes this one buffers,
+    /// see `UtpContext::stats`. `bytes` is only called for a shared UDP socket.
+    pub fn set_buffered<F: FnOnce() -> usize>(&self, bytes: F) {
+        if let Transport::Shared(ref endpoint) = *self {
+            endpoint.set_buffered(bytes());
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match *self {
+            Transport::Owned(ref socket) => socket.recv_from(buf),
+            Transport::Custom(ref transport) => transport.recv_from(buf),
+            Transport::Shared(ref endpoint) => {
+                let (datagram, src) = endpoint.wait(true)?
+                    .expect("A consumed datagram is always returned");
+                let len = ::std::cmp::min(datagram.len(), buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok((len, src))
+            }
+        }
+    }
+
+    /// Receives a datagram like `recv_from`, but fails with an error of kind `WouldBlock` rather
+    /// than wait if none is available, without switching the transport to non-blocking mode.
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match *self {
+            Transport::Owned(ref socket) => recv_nonblocking(socket, buf),
+            Transport::Custom(ref transport) => transport.try_recv_from(buf),
+            Transport::Shared(ref endpoint) => {
+                let (datagram, src) = endpoint.shared
+                    .wait(Slot::Connection(endpoint.id, endpoint.token), None, true, true)?
+                    .expect("A consumed datagram is always returned");
+                let len = ::std::cmp::min(datagram.len(), buf.len());
+                buf[..len].copy_from_slice(&datagram[..len]);
+                Ok((len, src))
+            }
+        }
+    }
+
+    /// Returns the congestion window a connection to `peer` may use, given its own `window`.
+    ///
+    /// With fair sharing enabled on a shared UDP socket, the connections to the same IP address
+    /// likely go through the same bottleneck, so they divide their combined windows equally.
+    /// Otherwise, this is the connection's own window.
+    pub fn share_window(&self, peer: IpAddr, window: u32) -> u32 {
+        match *self {
+            Transport::Owned(_) | Transport::Custom(_) => window,
+            Transport::Shared(ref endpoint) => endpoint.share_window(peer, window),
+        }
+    }
+
+    /// Returns a handle waking up the threads waiting for a datagram through this transport,
+    /// which doesn't keep the UDP socket open or the connection registered.
+    pub fn waker(&self) -> Waker {
+        match *self {
+            Transport::Owned(ref socket) => Waker::Owned(Arc::downgrade(socket)),
+            Transport::Shared(ref endpoint) => Waker::Shared(Arc::downgrade(endpoint)),
+            Transport::Custom(_) => Waker::Custom,
+        }
+    }
+
+    /// Waits up to `timeout` for a datagram to be available, without consuming it.
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<()> {
+        match *self {
+            Transport::Owned(ref socket) => DatagramTransport::wait_readable(&**socket, timeout),
+            Transport::Custom(ref transport) => transport.wait_readable(timeout),
+            Transport::Shared(ref endpoint) => {
+                let deadline = Instant::now() + timeout;
+                let _ = endpoint.shared.wait(Slot::Connection(endpoint.id, endpoint.token),
+                                             Some(deadline), false, false);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<UdpSocket> for Transport {
+    fn from(socket: UdpSocket) -> Transport {
+        Transport::Owned(Arc::new(socket))
+    }
+}
+
+/// Wakes up the threads waiting for a datagram through a transport, see `Transport::waker`.
+#[derive(Clone)]
+pub enum Waker {
+    /// A UDP socket used by a single connection.
+    Owned(Weak<UdpSocket>),
+    /// A connection's share of a UDP socket.
+    Shared(Weak<Endpoint>),
+    /// Another datagram transport, which can't be woken up: the waiting thread only notices once
+    /// its read timeout expires.
+    Custom,
+}
+
+impl Waker {
+    /// Wakes up the thread waiting for a datagram, if any, or else the next one to wait. Does
+    /// nothing once the transport is gone.
+    pub fn wake(&self) {
+        match *self {
+            Waker::Owned(ref socket) => {
+                if let Some(socket) = socket.upgrade() {
+                    wake_socket(&socket);
+                }
+            }
+            Waker::Shared(ref endpoint) => {
+                if let Some(endpoint) = endpoint.upgrade() {
+                    endpoint.wake();
+                }
+            }
+            Waker::Custom => (),
+        }
+    }
+}
+
+/// Ends the wait of a thread reading from `socket` by sending it an empty datagram, which isn't a
+/// valid packet and so gets dropped.
+fn wake_socket(socket: &UdpSocket) {
+    if let Ok(mut addr) = socket.local_addr() {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = socket.send_to(&[], addr);
+    }
+}
+
+/// The datagrams received on a shared UDP socket, sorted by connection.
+struct Routes {
+    /// The connections woken up by a `Waker` since they last waited, by token
+    woken: HashSet<u64>,
+    /// The connections, by the identifier of their incoming packets
+    connections: HashMap<u16, Vec<Route>>,
+    /// Connection requests for no known connection
+    requests: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// Whether a thread is currently reading from the UDP socket
+    reading: bool,
+    /// The token of the next registered connection
+    next_token: u64,
+    /// Limits the resets sent for unknown connections
+    resets: RateLimiter,
+    /// The deadlines of the connections' timers, with the token of their connection, earliest
+    /// first
+    deadlines: BTreeSet<(Instant, u64)>,
+    /// Whether connections to the same IP address share their congestion windows fairly
+    fair_sharing: bool,
+    /// The latest congestion window of each connection sharing it, by token, with the IP address
+    /// of its remote peer
+    windows: HashMap<u64, (IpAddr, u32)>,
+    /// The most bytes the connections may buffer altogether, if limited
+    buffer_budget: Option<usize>,
+    /// The bytes each connection may buffer, as reserved from the budget, and the bytes it last
+    /// reported buffering, by token
+    buffers: HashMap<u64, (usize, usize)>,
+}
+
+/// A connection sharing the UDP socket.
+struct Route {
+    token: u64,
+    /// The connection identifier the connection sends its packets with
+    sender_id: u16,
+    /// The remote peer, unless the connection accepts packets from any address, like connections
+    /// waiting for a reply from another address than the one they sent a request to
+    peer: Option<SocketAddr>,
+    queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    /// When the connection next needs its timers handled, if ever
+    deadline: Option<Instant>,
+}
+
+/// Where a datagram is delivered.
+#[derive(Clone, Copy)]
+enum Slot {
+    /// To a connection, by connection identifier and token.
+    Connection(u16, u64),
+    /// To the queue of connection requests.
+    Requests,
+}
+
+impl Routes {
+    /// Returns the connection receiving the packets with the given identifier from `src`, or if
+    /// `any_peer` is set and there's none, the one receiving them from any address.
+    fn find(&mut self, id: u16, src: SocketAddr, any_peer: bool) -> Option<&mut Route> {
+        let routes = self.connections.get_mut(&id)?;
+        let index = match routes.iter().position(|r| r.peer == Some(src)) {
+            Some(index) => index,
+            None if any_peer => routes.iter().position(|r| r.peer.is_none())?,
+            None => return None,
+        };
+        Some(&mut routes[index])
+    }
+
+    /// Returns the connection a reset with the given connection identifier from `src` is meant
+    /// for, preferring the connections with that remote peer over those accepting packets from
+    /// any address.
+    ///
+    /// A reset carries the identifier the connection receives with if the remote peer knows the
+    /// connection, or the one it sends with if the remote peer answers one of its packets. The
+    /// latter is the identifier the connection receives with minus one if it opened the
+    /// connection, or plus one if it accepted it.
+    fn find_reset(&mut self, id: u16, src: SocketAddr) -> Option<&mut Route> {
+        let location = [Some(src), None].iter().find_map(|&peer| {
+            [id, id.wrapping_sub(1), id.wrapping_add(1)].iter().find_map(|&receiver_id| {
+                let index = self.connections.get(&receiver_id)?.iter().position(|r| {
+                    r.peer == peer && (receiver_id == id || r.sender_id == id)
+                })?;
+                Some((receiver_id, index))
+            })
+        });
+        let (receiver_id, index) = location?;
+        self.connections.get_mut(&receiver_id).map(|routes| &mut routes[index])
+    }
+
+    fn queue(&mut self, slot: Slot) -> Option<&mut VecDeque<(Vec<u8>, SocketAddr)>> {
+        match slot {
+            Slot::Connection(id, token) => {
+                self.connections.get_mut(&id)?.iter_mut()
+                    .find(|r| r.token == token)
+                    .map(|r| &mut r.queue)
+            }
+            Slot::Requests => Some(&mut self.requests),
+        }
+    }
+
+    /// Hands a datagram over to the connection it belongs to.
+    ///
+    /// A connection request for a connection that was already accepted, because the reply got
+    /// lost, goes to that connection. Other requests wait for `accept`.
+    ///
+    /// Returns the reset to send back if the packet belongs to no known connection, so that the
+    /// remote peer gives up on it instead of sending it again and again.
+    fn dispatch(&mut self, datagram: Vec<u8>, src: SocketAddr) -> Option<Packet> {
+        let packet = match Packet::try_from(&datagram[..]) {
+            Ok(packet) => packet,
+            Err(e) => {
+                debug!("Ignoring invalid packet from {}: {}", src, e);
+                return None;
+            }
+        };
+        let is_syn = packet.get_type() == PacketType::Syn;
+        let id = if is_syn {
+            packet.connection_id().wrapping_add(1)
+        } else {
+            packet.connection_id()
+        };
+
+        let route = if packet.get_type() == PacketType::Reset {
+            self.find_reset(id, src)
+        } else {
+            self.find(id, src, !is_syn)
+        };
+
+        let queue = match route {
+            Some(route) => &mut route.queue,
+            None if is_syn => &mut self.requests,
+            None => return self.reject(&packet, src),
+        };
+        if queue.len() < MAX_QUEUED_DATAGRAMS {
+            queue.push_back((datagram, src));
+        } else {
+            debug!("Too many packets waiting for connection {}, dropping one", id);
+        }
+        None
+    }
+
+    /// Returns the reset answering a packet of an unknown connection, if any.
+    ///
+    /// Resets are never answered, and neither are the packets of connections still waiting for
+    /// `accept`, whose data may arrive right after the request.
+    fn reject(&mut self, packet: &Packet, src: SocketAddr) -> Option<Packet> {
+        let id = packet.connection_id();
+        let pending = self.requests.iter().any(|&(ref datagram, from)| {
+            from == src && Packet::try_from(&datagram[..])
+                .is_ok_and(|syn| syn.connection_id().wrapping_add(1) == id)
+        });
+        if packet.get_type() == PacketType::Reset || pending {
+            debug!("Ignoring packet from {} for unknown connection {}", src, id);
+            return None;
+        }
+        if !self.resets.allow() {
+            debug!("Too many packets for unknown connections, not resetting connection {}", id);
+            return None;
+        }
+
+        debug!("Resetting unknown connection {} of {}", id, src);
+        let mut reset = Packet::new();
+        reset.set_type(PacketType::Reset);
+        reset.set_connection_id(id);
+        reset.set_seq_nr(rand::random());
+        reset.set_ack_nr(packet.seq_nr());
+        Some(reset)
+    }
+}
+
+/// A UDP socket shared by several connections.
+pub struct Shared {
+    socket: UdpSocket,
+    routes: Mutex<Routes>,
+    /// Signaled whenever a datagram is queued or the reading thread stops reading
+    readable: Condvar,
+}
+
+/// A connection registered with a shared UDP socket.
+pub struct Endpoint {
+    shared: Arc<Shared>,
+    id: u16,
+    token: u64,
+    read_timeout: Mutex<Option<Duration>>,
+    nonblocking: AtomicBool,
+}
+
+impl Shared {
+    pub fn new(socket: UdpSocket) -> Shared {
+        Shared {
+            socket,
+            routes: Mutex::new(Routes {
+                connections: HashMap::new(),
+                requests: VecDeque::new(),
+                reading: false,
+                next_token: 0,
+                resets: RateLimiter::new(MAX_RESETS_PER_SECOND),
+                deadlines: BTreeSet::new(),
+                fair_sharing: false,
+                windows: HashMap::new(),
+                buffer_budget: None,
+                buffers: HashMap::new(),
+                woken: HashSet::new(),
+            }),
+            readable: Condvar::new(),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Routes> {
+        self.routes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Registers a connection receiving the packets with the given connection identifier, from
+    /// `peer` or, if `None`, from any address, and sending its own with `sender_id`.
+    ///
+    /// Returns `None` if a connection with the same identifier and peer is already registered.
+    pub fn register(shared: &Arc<Shared>, id: u16, sender_id: u16, peer: Option<SocketAddr>)
+                    -> Option<Endpoint> {
+        let mut routes = shared.lock();
+        let token = routes.next_token;
+        let connections = routes.connections.entry(id).or_default();
+        if connections.iter().any(|r| r.peer == peer) {
+            return None;
+        }
+        connections.push(Route { token, sender_id, peer, queue: VecDeque::new(), deadline: None });
+        routes.next_token += 1;
+        Some(Endpoint {
+            shared: shared.clone(),
+            id,
+            token,
+            read_timeout: Mutex::new(None),
+            nonblocking: AtomicBool::new(false),
+        })
+    }
+
+    /// Registers a connection about to be opened, receiving packets from any address with a
+    /// connection identifier no other connection uses.
+    pub fn register_new(shared: &Arc<Shared>) -> Endpoint {
+        loop {
+            let id = rand::random();
+            let known = shared.lock().connections.contains_key(&id);
+            if !known {
+                if let Some(endpoint) = Shared::register(shared, id, id.wrapping_add(1), None) {
+                    return endpoint;
+                }
+            }
+        }
+    }
+
+    /// Enables or disables the fair sharing of congestion windows, see `Transport::share_window`.
+    pub fn set_fair_sharing(&self, enabled: bool) {
+        let mut routes = self.lock();
+        routes.fair_sharing = enabled;
+        if !enabled {
+            routes.windows.clear();
+        }
+    }
+
+    /// Returns whether connections share their congestion windows fairly.
+    pub fn fair_sharing(&self) -> bool {
+        self.lock().fair_sharing
+    }
+
+    /// Limits the bytes the connections may buffer altogether, see `Transport::reserve_buffers`.
+    pub fn set_buffer_budget(&self, budget: Option<usize>) {
+        self.lock().buffer_budget = budget;
+    }
+
+    /// Returns the most bytes the connections may buffer altogether, if limited.
+    pub fn buffer_budget(&self) -> Option<usize> {
+        self.lock().buffer_budget
+    }
+
+    /// Returns the number of registered connections, the bytes they reserved for their buffers
+    /// and the bytes they last reported buffering.
+    pub fn buffer_usage(&self) -> (usize, usize, usize) {
+        let routes = self.lock();
+        let connections = routes.connections.values().map(Vec::len).sum();
+        let (reserved, buffered) = routes.buffers.values()
+            .fold((0, 0), |(reserved, buffered), &(r, b)| (reserved + r, buffered + b));
+        (connections, reserved, buffered)
+    }
+
+    /// Returns the earliest deadline of the registered connections' timers, if any.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.lock().deadlines.iter().next().map(|&(deadline, _)| deadline)
+    }
+
+    /// Returns the tokens of the registered connections whose timers are due by `now`.
+    ///
+    /// Their deadlines stay in place until the connections handle their timers and replace them.
+    pub fn due_connections(&self, now: Instant) -> HashSet<u64> {
+        self.lock().deadlines.range(..=(now, u64::MAX)).map(|&(_, token)| token).collect()
+    }
+
+    /// Waits for the next connection request, returning it along with its source.
+    pub fn next_request(&self) -> io::Result<(Vec<u8>, SocketAddr)> {
+        Ok(self.wait(Slot::Requests, None, false, true)?
+            .expect("A consumed datagram is always returned"))
+    }
+
+    /// Waits until a datagram is available in `slot`, returning it if `consume` is set.
+    ///
+    /// A single thread at a time reads from the UDP socket, queuing the datagrams it receives for
+    /// the connections they belong to, while the others wait for it to queue theirs or to stop
+    /// reading. Times out with an error of kind `ErrorKind::TimedOut` once `deadline` passes, or
+    /// fails with `ErrorKind::WouldBlock` right away if `nonblocking` is set.
+    fn wait(&self, slot: Slot, deadline: Option<Instant>, nonblocking: bool, consume: bool)
+            -> io::Result<Option<(Vec<u8>, SocketAddr)>> {
+        let mut routes = self.lock();
+        loop {
+            match routes.queue(slot) {
+                Some(ref mut queue) if !queue.is_empty() => {
+                    return Ok(if consume { queue.pop_front() } else { None });
+                }
+                Some(_) => (),
+                None => return Err(io::Error::new(ErrorKind::NotConnected,
+                                                  "the connection is no longer registered")),
+            }
+
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining == Duration::from_secs(0) {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+                    }
+                    Some(remaining)
+                }
+                None => None,
+            };
+
+            // Give up waiting if woken up, letting the connection find out why
+            if let Slot::Connection(_, token) = slot {
+                if !nonblocking && routes.woken.remove(&token) {
+                    return Err(io::Error::new(ErrorKind::Interrupted, "woken up"));
+                }
+            }
+
+            // Another thread is reading, it'll signal once it queued something
+            if routes.reading {
+                if nonblocking {
+                    return Err(io::Error::new(ErrorKind::WouldBlock, "would block"));
+                }
+                routes = match remaining {
+                    Some(remaining) => {
+                        self.readable.wait_timeout(routes, remaining)
+                            .unwrap_or_else(|e| e.into_inner()).0
+                    }
+                    None => self.readable.wait(routes).unwrap_or_else(|e| e.into_inner()),
+                };
+                continue;
+            }
+
+            routes.reading = true;
+            drop(routes);
+            let result = self.read_datagram(remaining, nonblocking);
+            routes = self.lock();
+            routes.reading = false;
+            self.readable.notify_all();
+
+            match result {
+                Ok((datagram, src)) => {
+                    if let Some(reset) = routes.dispatch(datagram, src) {
+                        let _ = self.socket.send_to(reset.as_ref(), src);
+                    }
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                              e.kind() == ErrorKind::TimedOut => {
+                    if nonblocking {
+                        return Err(io::Error::new(ErrorKind::WouldBlock, "would block"));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads a single datagram from the UDP socket, waiting at most `timeout`.
+    fn read_datagram(&self, timeout: Option<Duration>, nonblocking: bool)
+                     -> io::Result<(Vec<u8>, SocketAddr)> {
+        let mut buf = [0; BUF_SIZE + HEADER_SIZE];
+        let result = if nonblocking {
+            recv_nonblocking(&self.socket, &mut buf)
+        } else {
+            self.socket.set_read_timeout(timeout)?;
+            self.socket.recv_from(&mut buf)
+        };
+        result.map(|(read, src)| (buf[..read].to_vec(), src))
+    }
+}
+
+impl Endpoint {
+    /// Returns the identifier of the connection's incoming packets.
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    /// Replaces the deadline of the connection's timers.
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        let mut routes = self.shared.lock();
+        let previous = match routes.connections.get_mut(&self.id)
+                                   .and_then(|c| c.iter_mut().find(|r| r.token == self.token)) {
+            Some(route) if route.deadline == deadline => return,
+            Some(route) => ::std::mem::replace(&mut route.deadline, deadline),
+            None => return,
+        };
+        if let Some(previous) = previous {
+            routes.deadlines.remove(&(previous, self.token));
+        }
+        if let Some(deadline) = deadline {
+            routes.deadlines.insert((deadline, self.token));
+        }
+    }
+
+    /// Records the connection's congestion window, and returns its share of the combined windows
+    /// of the connections to `peer`, if fair sharing is enabled.
+    fn share_window(&self, peer: IpAddr, window: u32) -> u32 {
+        let mut routes = self.shared.lock();
+        if !routes.fair_sharing {
+            return window;
+        }
+        routes.windows.insert(self.token, (peer, window));
+        let (total, count) = routes.windows.values()
+            .filter(|&&(ip, _)| ip == peer)
+            .fold((0u64, 0u64), |(total, count), &(_, w)| (total + u64::from(w), count + 1));
+        (total / count) as u32
+    }
+
+    /// Reserves between `min` and `max` bytes of the buffer budget for the connection, see
+    /// `Transport::reserve_buffers`.
+    fn reserve_buffers(&self, min: usize, max: usize) -> Option<usize> {
+        let mut routes = self.shared.lock();
+        let available = match routes.buffer_budget {
+            Some(budget) => {
+                let others = routes.buffers.iter()
+                    .filter(|&(&token, _)| token != self.token)
+                    .fold(0, |total, (_, &(reserved, _))| total + reserved);
+                budget.saturating_sub(others)
+            }
+            None => usize::MAX,
+        };
+        if available < min {
+            return None;
+        }
+        let reserved = ::std::cmp::min(available, max);
+        routes.buffers.entry(self.token).or_insert((0, 0)).0 = reserved;
+        Some(reserved)
+    }
+
+    /// Records the bytes the connection buffers.
+    fn set_buffered(&self, bytes: usize) {
+        self.shared.lock().buffers.entry(self.token).or_insert((0, 0)).1 = bytes;
+    }
+
+    /// Makes the thread waiting for the connection's datagrams, or the next one to, give up
+    /// waiting, whether it's reading from the UDP socket itself or waiting for another thread to.
+    fn wake(&self) {
+        self.shared.lock().woken.insert(self.token);
+        self.shared.readable.notify_all();
+        wake_socket(&self.shared.socket);
+    }
+
+    fn wait(&self, consume: bool) -> io::Result<Option<(Vec<u8>, SocketAddr)>> {
+        let timeout = *self.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+        let deadline = timeout.map(|t| Instant::now() + t);
+        self.shared.wait(Slot::Connection(self.id, self.token), deadline,
+                         self.nonblocking.load(Ordering::Relaxed), consume)
+    }
+}
+
+impl Drop for Endpoint {
+    /// Unregisters the connection, dropping the packets it didn't read.
+    fn drop(&mut self) {
+        self.set_deadline(None);
+        let mut routes = self.shared.lock();
+        routes.windows.remove(&self.token);
+        routes.buffers.remove(&self.token);
+        routes.woken.remove(&self.token);
+        let empty = match routes.connections.get_mut(&self.id) {
+            Some(connections) => {
+                connections.retain(|r| r.token != self.token);
+                connections.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            routes.connections.remove(&self.id);
+        }
+    }
+}
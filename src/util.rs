@@ -1,20 +1,31 @@
+use std::cmp::min;
 use std::ops::Sub;
-use num_traits::ToPrimitive;
-use rand::{self, Rng};
+use std::time::{Duration, Instant};
+use num_traits::{ToPrimitive, WrappingSub};
+use rand::Rng;
 
 /// Calculate the exponential weighted moving average for a vector of numbers, with a smoothing
 /// factor `alpha` between 0 and 1. A higher `alpha` discounts older observations faster.
-pub fn ewma<'a, T, I>(mut samples: I, alpha: f64) -> f64
+pub fn ewma<'a, T, I>(samples: I, alpha: f64) -> f64
     where T: ToPrimitive + 'a,
           I: Iterator<Item = &'a T>
 {
-    let first = samples.next().map_or(0.0, |v| v.to_f64().unwrap());
-    samples.map(|v| v.to_f64().unwrap())
-           .fold(first, |avg, sample| alpha * sample + (1.0 - alpha) * avg)
+    ewma_owned(samples.map(|v| v.to_f64().unwrap()), alpha)
+}
+
+/// Calculate the exponential weighted moving average of a sequence of samples, like `ewma`, but
+/// taking them by value so they don't need to be collected first.
+pub fn ewma_owned<I: Iterator<Item = f64>>(mut samples: I, alpha: f64) -> f64 {
+    let first = samples.next().unwrap_or(0.0);
+    samples.fold(first, |avg, sample| alpha * sample + (1.0 - alpha) * avg)
 }
 
 /// Returns the absolute difference between two values.
-pub fn abs_diff<T: PartialOrd + Sub<Output = U>, U>(a: T, b: T) -> U {
+///
+/// The smaller value is always subtracted from the larger one, so this can't overflow for totally
+/// ordered types such as `Duration`. Sequence numbers and timestamps wrap around instead, and need
+/// `wrapping_abs_diff`.
+pub fn abs_diff<T: Ord + Sub<Output = T>>(a: T, b: T) -> T {
     if a > b {
         a - b
     } else {
@@ -22,13 +33,36 @@ pub fn abs_diff<T: PartialOrd + Sub<Output = U>, U>(a: T, b: T) -> U {
     }
 }
 
+/// Returns the modular distance between two wrapping integers, such as sequence numbers or
+/// timestamps.
+///
+/// That's the shortest way from one to the other around the wrap boundary, in either direction:
+/// the distance between `u16::MAX` and `0` is 1, not `u16::MAX`. It never overflows.
+pub fn wrapping_abs_diff<T: WrappingSub + Ord>(a: T, b: T) -> T {
+    min(a.wrapping_sub(&b), b.wrapping_sub(&a))
+}
+
+/// Returns whether sequence number `a` precedes sequence number `b`.
+///
+/// Sequence numbers wrap around, so they are compared by their modular distance: `a` precedes
+/// `b` if `b` is less than half the sequence number space ahead of `a`.
+pub fn seq_less_than(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+/// Returns whether sequence number `a` precedes or is equal to sequence number `b`.
+///
+/// See `seq_less_than`.
+pub fn seq_less_than_or_equal(a: u16, b: u16) -> bool {
+    a == b || seq_less_than(a, b)
+}
+
 /// Safely generates two sequential connection identifiers.
 ///
 /// This avoids an overflow when the generated receiver identifier is the largest
 /// representable value in u16 and it is incremented to yield the corresponding sender
 /// identifier.
-pub fn generate_sequential_identifiers() -> (u16, u16) {
-    let mut rng = rand::thread_rng();
+pub fn generate_sequential_identifiers<R: Rng>(rng: &mut R) -> (u16, u16) {
     let id = rng.gen::<u16>();
     if id.checked_add(1).is_some() {
         (id, id + 1)
@@ -37,9 +71,62 @@ pub fn generate_sequential_identifiers() -> (u16, u16) {
     }
 }
 
+/// Lookup table of the CRC-32C (Castagnoli) of every byte value.
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// Calculates the CRC-32C (Castagnoli) checksum of `data`, as used by iSCSI and SCTP.
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Limits how often something happens, to at most a given number of times per second.
+pub struct RateLimiter {
+    limit: u32,
+    count: u32,
+    since: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32) -> RateLimiter {
+        RateLimiter { limit, count: 0, since: Instant::now() }
+    }
+
+    /// Returns whether it may happen once more right now, counting it if so.
+    pub fn allow(&mut self) -> bool {
+        if self.since.elapsed() >= Duration::from_secs(1) {
+            self.since = Instant::now();
+            self.count = 0;
+        }
+        if self.count < self.limit {
+            self.count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use util::*;
+    use crate::util::*;
 
     #[test]
     fn test_ewma_empty_vector() {
@@ -50,7 +137,7 @@ mod test {
 
     #[test]
     fn test_ewma_one_element() {
-        let input = vec![1u32];
+        let input = [1u32];
         let alpha = 1.0 / 3.0;
         assert_eq!(ewma(input.iter(), alpha), 1.0);
     }
@@ -72,6 +159,29 @@ mod test {
         assert_eq!(ewma(input.iter(), alpha), expected[expected.len() - 1]);
     }
 
+    #[test]
+    fn test_ewma_owned_empty_iterator() {
+        let alpha = 1.0 / 3.0;
+        assert_eq!(ewma_owned(::std::iter::empty(), alpha), 0.0);
+    }
+
+    #[test]
+    fn test_ewma_owned_one_element() {
+        let alpha = 1.0 / 3.0;
+        assert_eq!(ewma_owned(::std::iter::once(1.0), alpha), 1.0);
+    }
+
+    #[test]
+    fn test_exponential_smoothed_moving_average_owned() {
+        let alpha = 1.0 / 3.0;
+        let expected = 158488.0 / 19683.0;
+        assert_eq!(ewma_owned((1..11).map(f64::from), alpha), expected);
+
+        // Same as the reference implementation
+        let input = (1u32..11).collect::<Vec<u32>>();
+        assert_eq!(ewma_owned(input.iter().map(|&v| v as f64), alpha), ewma(input.iter(), alpha));
+    }
+
     #[test]
     fn test_abs_diff() {
         let a = 10;
@@ -79,4 +189,55 @@ mod test {
         assert_eq!(abs_diff(a, b), 5);
         assert_eq!(abs_diff(b, a), 5);
     }
+
+    #[test]
+    fn test_wrapping_abs_diff() {
+        assert_eq!(wrapping_abs_diff(10u16, 5), 5);
+        assert_eq!(wrapping_abs_diff(5u16, 10), 5);
+        assert_eq!(wrapping_abs_diff(7u16, 7), 0);
+
+        // Sequence numbers across the wrap boundary
+        assert_eq!(wrapping_abs_diff(u16::MAX, 0), 1);
+        assert_eq!(wrapping_abs_diff(0, u16::MAX), 1);
+        assert_eq!(wrapping_abs_diff(u16::MAX - 10, 10), 21);
+
+        // Half the space away is as far as it gets, either way
+        assert_eq!(wrapping_abs_diff(0u16, 0x8000), 0x8000);
+        assert_eq!(wrapping_abs_diff(0x8000u16, 0), 0x8000);
+        assert_eq!(wrapping_abs_diff(0u16, 0x8001), 0x7fff);
+
+        // Timestamps, in microseconds, across the wrap boundary
+        assert_eq!(wrapping_abs_diff(u32::MAX - 999, 1000), 2000);
+        assert_eq!(wrapping_abs_diff(1000, u32::MAX - 999), 2000);
+    }
+
+    #[test]
+    fn test_seq_less_than() {
+        assert!(seq_less_than(1, 2));
+        assert!(!seq_less_than(2, 1));
+        assert!(!seq_less_than(1, 1));
+        assert!(seq_less_than_or_equal(1, 1));
+
+        // Across the wrap boundary
+        assert!(seq_less_than(u16::MAX, 0));
+        assert!(seq_less_than(u16::MAX - 10, 10));
+        assert!(!seq_less_than(10, u16::MAX - 10));
+        assert!(seq_less_than_or_equal(u16::MAX, 0));
+        assert!(!seq_less_than_or_equal(0, u16::MAX));
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0; 32]), 0x8a91_36aa);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.allow());
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+    }
 }
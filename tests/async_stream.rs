@@ -0,0 +1,163 @@
+#![cfg(feature = "tokio")]
+extern crate tokio;
+extern crate utp;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::thread;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::{Builder, Runtime};
+use utp::{AsyncUtpListener, AsyncUtpStream, UtpStream};
+
+macro_rules! iotry {
+    ($e:expr) => (match $e { Ok(e) => e, Err(e) => panic!("{}", e) })
+}
+
+fn next_test_addr() -> SocketAddr {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_OFFSET: AtomicUsize = AtomicUsize::new(0);
+    const BASE_PORT: u16 = 9800;
+    let port = BASE_PORT + NEXT_OFFSET.fetch_add(1, Ordering::Relaxed) as u16;
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+fn runtime() -> Runtime {
+    iotry!(Builder::new_current_thread().enable_all().build())
+}
+
+#[test]
+fn test_async_stream_connect() {
+    // Takes several round trips, and more than the initial windows
+    const LEN: usize = 200_000;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let server_addr = next_test_addr();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // Sends the data back, then waits for the client to close the connection
+    let child = thread::spawn(move || {
+        let mut received = vec![0; LEN];
+        iotry!(server.read_exact(&mut received));
+        iotry!(server.write_all(&received));
+        let mut rest = vec!();
+        iotry!(server.read_to_end(&mut rest));
+        assert!(rest.is_empty());
+        iotry!(server.close());
+    });
+
+    runtime().block_on(async {
+        let mut client = iotry!(AsyncUtpStream::connect(server_addr).await);
+        assert_eq!(iotry!(client.peer_addr()), server_addr);
+        iotry!(client.write_all(&data).await);
+        iotry!(client.flush().await);
+        let mut echoed = vec![0; LEN];
+        iotry!(client.read_exact(&mut echoed).await);
+        assert_eq!(echoed, data);
+        iotry!(client.shutdown().await);
+    });
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_async_listener_accepts_several_connections() {
+    const CLIENTS: usize = 3;
+    let runtime = runtime();
+    let listener = iotry!(runtime.block_on(AsyncUtpListener::bind(next_test_addr())));
+    let server_addr = iotry!(listener.local_addr());
+
+    let child = thread::spawn(move || {
+        for idx in 0..CLIENTS {
+            let mut client = iotry!(UtpStream::connect(server_addr));
+            iotry!(client.write_all(&[idx as u8; 10_000]));
+            iotry!(client.close());
+        }
+    });
+
+    runtime.block_on(async {
+        for idx in 0..CLIENTS {
+            let (mut server, src) = iotry!(listener.accept().await);
+            assert_eq!(iotry!(server.peer_addr()), src);
+            assert!(iotry!(server.local_addr()) != server_addr);
+            let mut received = vec!();
+            iotry!(server.read_to_end(&mut received).await);
+            assert_eq!(received, vec![idx as u8; 10_000]);
+            iotry!(server.shutdown().await);
+        }
+    });
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_async_streams() {
+    const LEN: usize = 200_000;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let expected = data.clone();
+
+    runtime().block_on(async move {
+        let listener = iotry!(AsyncUtpListener::bind(next_test_addr()).await);
+        let server_addr = iotry!(listener.local_addr());
+
+        // Both ends run on the same thread
+        let server = tokio::spawn(async move {
+            let (mut server, _src) = iotry!(listener.accept().await);
+            let mut received = vec!();
+            iotry!(server.read_to_end(&mut received).await);
+            iotry!(server.shutdown().await);
+            received
+        });
+
+        let mut client = iotry!(AsyncUtpStream::connect(server_addr).await);
+        iotry!(client.write_all(&data).await);
+        iotry!(client.shutdown().await);
+        assert_eq!(iotry!(server.await), expected);
+    });
+}
+
+#[test]
+fn test_async_stream_connect_retries() {
+    use std::time::{Duration, Instant};
+    let server_addr = next_test_addr();
+
+    // The first connection request arrives before anyone listens, and goes unanswered
+    let child = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        let mut server = iotry!(UtpStream::bind(server_addr));
+        let mut received = vec!();
+        iotry!(server.read_to_end(&mut received));
+        received
+    });
+
+    runtime().block_on(async {
+        let start = Instant::now();
+        let mut client = iotry!(AsyncUtpStream::connect(server_addr).await);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        iotry!(client.write_all(b"hello").await);
+        iotry!(client.shutdown().await);
+    });
+    assert_eq!(child.join().unwrap(), b"hello");
+}
+
+#[test]
+fn test_async_stream_closes_on_drop() {
+    const LEN: usize = 100_000;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let expected = data.clone();
+    let server_addr = next_test_addr();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // Everything sent arrives, followed by the end of the stream
+    let child = thread::spawn(move || {
+        let mut received = vec!();
+        iotry!(server.read_to_end(&mut received));
+        received
+    });
+
+    runtime().block_on(async {
+        let mut client = iotry!(AsyncUtpStream::connect(server_addr).await);
+        iotry!(client.write_all(&data).await);
+        drop(client);
+
+        // The closing handshake goes on in the background, as long as the runtime runs
+        let received = iotry!(tokio::task::spawn_blocking(move || child.join()).await);
+        assert_eq!(received.unwrap(), expected);
+    });
+}
@@ -0,0 +1,36 @@
+#![cfg(feature = "serde")]
+extern crate serde_json;
+extern crate utp;
+
+use utp::{Extension, ExtensionType, Packet, PacketHeader, PacketType};
+
+#[test]
+fn test_packet_json_round_trip() {
+    let mut packet = Packet::with_payload(b"abc");
+    packet.set_type(PacketType::State);
+    packet.set_seq_nr(5);
+    packet.set_ack_nr(4);
+    packet.set_sack(vec![1, 0, 0, 0]);
+
+    let json = serde_json::to_string(&packet).unwrap();
+    let decoded: Packet = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, packet);
+    assert_eq!(decoded.as_ref(), packet.as_ref());
+
+    let header = PacketHeader::decode(packet.as_ref()).unwrap();
+    let json = serde_json::to_string(&header).unwrap();
+    assert_eq!(serde_json::from_str::<PacketHeader>(&json).unwrap(), header);
+}
+
+#[test]
+fn test_extension_json_round_trip() {
+    let extension = Extension::new(1, vec![1, 0, 0, 0]);
+    assert_eq!(extension.get_type(), ExtensionType::SelectiveAck);
+    let json = serde_json::to_string(&extension).unwrap();
+    assert_eq!(serde_json::from_str::<Extension>(&json).unwrap(), extension);
+
+    for kind in [ExtensionType::None, ExtensionType::Checksum, ExtensionType::Unknown(7)] {
+        let json = serde_json::to_string(&kind).unwrap();
+        assert_eq!(serde_json::from_str::<ExtensionType>(&json).unwrap(), kind);
+    }
+}
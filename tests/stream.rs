@@ -1,3 +1,5 @@
+#![cfg_attr(feature = "unstable", feature(read_buf, core_io_borrowed_buf))]
+extern crate rand;
 extern crate utp;
 
 use std::thread;
@@ -9,8 +11,8 @@ macro_rules! iotry {
 }
 
 fn next_test_port() -> u16 {
-    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
-    static NEXT_OFFSET: AtomicUsize = ATOMIC_USIZE_INIT;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static NEXT_OFFSET: AtomicUsize = AtomicUsize::new(0);
     const BASE_PORT: u16 = 9600;
     BASE_PORT + NEXT_OFFSET.fetch_add(1, Ordering::Relaxed) as u16
 }
@@ -82,6 +84,34 @@ fn test_stream_small_data() {
     assert!(child.join().is_ok());
 }
 
+#[test]
+fn test_stream_partial_reads() {
+    // A single packet, read through a buffer a tenth its size
+    const LEN: usize = 1000;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+
+    let d = data.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write(&d[..]));
+        iotry!(client.close());
+    });
+
+    // What doesn't fit in the buffer is kept for the next read
+    let mut received = Vec::with_capacity(LEN);
+    for _ in 0..10 {
+        let mut buf = [0; 100];
+        assert_eq!(iotry!(server.read(&mut buf)), 100);
+        received.extend_from_slice(&buf);
+    }
+    assert_eq!(received, data);
+    assert_eq!(iotry!(server.read(&mut [0; 100])), 0);
+    assert!(child.join().is_ok());
+}
+
 #[test]
 fn test_stream_large_data() {
     // Has to be sent over several packets
@@ -107,6 +137,79 @@ fn test_stream_large_data() {
     assert!(child.join().is_ok());
 }
 
+#[test]
+fn test_stream_write_all_under_small_windows() {
+    // Many times what either side buffers, so writes keep waiting for room
+    const LEN: usize = 5 * 1024 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|idx| (idx / 7) as u8).collect();
+
+    let d = data.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    iotry!(server.set_recv_buffer_size(64 * 1024));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.set_send_buffer_size(16 * 1024));
+        iotry!(client.write_all(&d[..]));
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received.len(), data.len());
+    assert!(received == data);
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_random_payload() {
+    // Several megabytes, keeping the congestion window busy for a while
+    const LEN: usize = 4 * 1024 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|_| rand::random()).collect();
+
+    let d = data.clone();
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        for chunk in d.chunks(64 * 1024) {
+            iotry!(client.write_all(chunk));
+        }
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received.len(), data.len());
+    assert!(received == data, "Received data differs from the data sent");
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_write_vectored() {
+    use std::io::IoSlice;
+    let parts: Vec<Vec<u8>> = (0..20).map(|n| vec![n as u8; 100 * n]).collect();
+    let data = parts.concat();
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        let slices: Vec<IoSlice> = parts.iter().map(|part| IoSlice::new(part)).collect();
+        let written = iotry!(client.write_vectored(&slices));
+        assert_eq!(written, parts.iter().map(Vec::len).sum::<usize>());
+        iotry!(client.close());
+    });
+
+    let mut received = Vec::new();
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received, data);
+    assert!(child.join().is_ok());
+}
+
 #[test]
 fn test_stream_successive_reads() {
     const LEN: usize = 1024;
@@ -133,6 +236,113 @@ fn test_stream_successive_reads() {
     assert!(child.join().is_ok());
 }
 
+#[test]
+fn test_stream_read_to_end() {
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(b"hello"));
+        iotry!(client.close());
+    });
+
+    // The end of the stream comes right after the data
+    let mut received = Vec::new();
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received, b"hello");
+    assert_eq!(iotry!(server.read(&mut [0; 10])), 0);
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_closes_on_drop() {
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // Dropping the stream without closing it still ends the connection gracefully
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(b"hello"));
+        drop(client);
+    });
+
+    let mut received = Vec::new();
+    iotry!(server.read_to_end(&mut received));
+    assert_eq!(received, b"hello");
+    iotry!(server.close());
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_simultaneous_close() {
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant};
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    let barrier = Arc::new(Barrier::new(2));
+    let b = barrier.clone();
+
+    // Both FIN packets cross each other, each acknowledging the other
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(b"hello"));
+        b.wait();
+        iotry!(client.close());
+    });
+
+    let mut buf = [0; 5];
+    iotry!(server.read_exact(&mut buf));
+    assert_eq!(&buf, b"hello");
+    barrier.wait();
+    let start = Instant::now();
+    iotry!(server.close());
+    assert!(child.join().is_ok());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn test_stream_buffered_lines() {
+    use std::io::{BufRead, BufReader};
+    let server_addr = next_test_ip4();
+    let server = iotry!(UtpStream::bind(server_addr));
+
+    // Several lines in a single packet
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(b"one\ntwo\nthree\n"));
+        iotry!(client.close());
+    });
+
+    let lines: Vec<String> = BufReader::new(server).lines().map(|line| iotry!(line)).collect();
+    assert_eq!(lines, vec!["one", "two", "three"]);
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_shutdown_write() {
+    use std::net::Shutdown;
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // The request ends with the client's half of the stream, the reply with the server's
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(b"ping"));
+        iotry!(client.shutdown(Shutdown::Write));
+        let mut reply = Vec::new();
+        iotry!(client.read_to_end(&mut reply));
+        assert_eq!(reply, b"pong");
+    });
+
+    let mut request = Vec::new();
+    iotry!(server.read_to_end(&mut request));
+    assert_eq!(request, b"ping");
+    iotry!(server.write_all(b"pong"));
+    iotry!(server.close());
+    assert!(child.join().is_ok());
+}
+
 #[test]
 fn test_local_addr() {
     use std::net::ToSocketAddrs;
@@ -144,3 +354,221 @@ fn test_local_addr() {
     assert!(stream.local_addr().is_ok());
     assert_eq!(stream.local_addr().unwrap(), addr);
 }
+
+#[test]
+fn test_peer_addr() {
+    use std::net::ToSocketAddrs;
+
+    let server_addr = next_test_ip4();
+    let server_addr = server_addr.to_socket_addrs().unwrap().next().unwrap();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // Not connected yet
+    assert!(server.peer_addr().is_err());
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        assert_eq!(iotry!(client.peer_addr()), server_addr);
+        iotry!(client.close());
+    });
+
+    let mut received = vec!();
+    iotry!(server.read_to_end(&mut received));
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_nonblocking_read() {
+    use std::io::ErrorKind;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    let (tx, rx) = channel();
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write(&[1, 2, 3]));
+        rx.recv().unwrap();
+        iotry!(client.close());
+    });
+
+    // Read the data sent right after connecting
+    let mut buf = [0; 10];
+    assert_eq!(iotry!(server.read(&mut buf)), 3);
+
+    iotry!(server.set_nonblocking(true));
+    match server.read(&mut buf) {
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+        x => panic!("Expected Err(WouldBlock), got {:?}", x),
+    }
+
+    tx.send(()).unwrap();
+    loop {
+        match server.read(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => panic!("Unexpected data"),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(10))
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_concurrent_read_and_write() {
+    const LEN: usize = 64 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    // Echo everything back
+    let child = thread::spawn(move || {
+        let mut buf = [0; 4096];
+        loop {
+            match iotry!(server.read(&mut buf)) {
+                0 => break,
+                n => iotry!(server.write_all(&buf[..n])),
+            }
+        }
+    });
+
+    let mut client = iotry!(UtpStream::connect(server_addr));
+    let mut reader = iotry!(client.try_clone());
+
+    // The reader blocks waiting for the echo while the client writes
+    let echo = thread::spawn(move || {
+        let mut received = vec![0; LEN];
+        iotry!(reader.read_exact(&mut received));
+        received
+    });
+
+    for chunk in data.chunks(1000) {
+        iotry!(client.write_all(chunk));
+    }
+    let received = echo.join().unwrap();
+    assert_eq!(received, data);
+
+    iotry!(client.close());
+    assert!(child.join().is_ok());
+}
+
+#[test]
+fn test_stream_concurrent_writes_from_clones() {
+    const LEN: usize = 64 * 1024;
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut received = Vec::with_capacity(2 * LEN);
+        iotry!(server.read_to_end(&mut received));
+        received
+    });
+
+    // One clone writes even bytes, the other odd ones
+    let mut client = iotry!(UtpStream::connect(server_addr));
+    let writers: Vec<_> = (0..2u8).map(|parity| {
+        let mut writer = iotry!(client.try_clone());
+        thread::spawn(move || {
+            let data: Vec<u8> = (0..LEN).map(|idx| (idx % 128) as u8 * 2 + parity).collect();
+            for chunk in data.chunks(1000) {
+                iotry!(writer.write_all(chunk));
+            }
+        })
+    }).collect();
+    for writer in writers {
+        assert!(writer.join().is_ok());
+    }
+    iotry!(client.close());
+
+    // Writes may interleave, but each clone's data arrives whole and in order
+    let received = child.join().unwrap();
+    assert_eq!(received.len(), 2 * LEN);
+    for parity in 0..2u8 {
+        let expected: Vec<u8> = (0..LEN).map(|idx| (idx % 128) as u8 * 2 + parity).collect();
+        let own: Vec<u8> = received.iter().cloned().filter(|byte| byte % 2 == parity).collect();
+        assert_eq!(own, expected);
+    }
+}
+
+#[test]
+fn test_stream_split() {
+    use std::sync::mpsc::channel;
+
+    const LEN: usize = 16 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let d = data.clone();
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+    let (tx, rx) = channel();
+
+    // Answer the request, then wait for the client to close the connection
+    let child = thread::spawn(move || {
+        let mut request = [0; 5];
+        iotry!(server.read_exact(&mut request));
+        assert_eq!(&request, b"hello");
+        iotry!(server.write_all(&d[..]));
+        iotry!(server.flush());
+        tx.send(()).unwrap();
+
+        let mut received = vec!();
+        iotry!(server.read_to_end(&mut received));
+        assert!(received.is_empty());
+    });
+
+    let client = iotry!(UtpStream::connect(server_addr));
+    let (mut reader, mut writer) = client.into_split();
+
+    let writer = thread::spawn(move || {
+        iotry!(writer.write_all(b"hello"));
+        rx.recv().unwrap();
+        iotry!(writer.close());
+    });
+
+    // Reading goes on until the writing half closes the connection
+    let mut received = Vec::with_capacity(LEN);
+    iotry!(reader.read_to_end(&mut received));
+    assert_eq!(received, data);
+    assert!(writer.join().is_ok());
+    assert!(child.join().is_ok());
+}
+
+#[test]
+#[cfg(feature = "unstable")]
+fn test_stream_read_buf_into_uninitialized_memory() {
+    use std::io::BorrowedBuf;
+    use std::mem::MaybeUninit;
+
+    const LEN: usize = 8 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|idx| idx as u8).collect();
+    let d = data.clone();
+
+    let server_addr = next_test_ip4();
+    let mut server = iotry!(UtpStream::bind(server_addr));
+
+    let child = thread::spawn(move || {
+        let mut client = iotry!(UtpStream::connect(server_addr));
+        iotry!(client.write_all(&d[..]));
+        iotry!(client.close());
+    });
+
+    let mut storage = vec![MaybeUninit::<u8>::uninit(); 2 * LEN];
+    let mut buf = BorrowedBuf::from(&mut storage[..]);
+    loop {
+        let before = buf.len();
+        iotry!(server.read_buf(buf.unfilled()));
+        if buf.len() == before {
+            break;
+        }
+    }
+
+    // Only the bytes read are marked as filled
+    assert_eq!(buf.len(), LEN);
+    assert_eq!(buf.filled(), &data[..]);
+    assert!(child.join().is_ok());
+}